@@ -0,0 +1,326 @@
+use flate2::{Decompress, FlushDecompress};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+
+use crate::{
+    packets::{
+        ConfigurationDisconnect, Disconnect, PlayDisconnect,
+        packet_read::MAX_PACKET_LENGTH,
+        packet_write::{WritePacketExt, WriteVersionedPacketError, WriteVersionedPacketExt},
+        protocol::ProtocolVersion,
+    },
+    types::{MCData, MCString, Uuid, VarInt},
+};
+
+// The ids of the login packets that change how the following packets are sent, they are the same for all versions
+const LOGIN_DISCONNECT: i32 = 0x00;
+const ENCRYPTION_REQUEST: i32 = 0x01;
+const LOGIN_SUCCESS: i32 = 0x02;
+const SET_COMPRESSION: i32 = 0x03;
+
+// Set Compression was added in 1.8
+const COMPRESSION_PROTOCOL: i32 = 47;
+
+// Login Success contains the uuid as 16 bytes instead of a string since 1.16
+const BINARY_UUID_PROTOCOL: i32 = 735;
+
+// The most a compressed packet is decompressed to, vanilla clients don't accept larger packets either
+const MAX_DECOMPRESSED_LENGTH: usize = 1 << 23;
+
+// Part of the message Spigot disconnects players with when it expects BungeeCord forwarding, but the handshake has no valid forwarding data
+const FORWARDING_REJECTED_MESSAGE: &str = "IP forwarding";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Login,
+    // Only exists since 1.20.2
+    Configuration,
+    Play,
+}
+
+// Follows the packets the backend sends to the client, so the proxy can send the client a disconnect it understands at any point
+pub struct ClientboundState {
+    protocol: i32,
+    // The uuid the backend should log the player in with
+    forwarded_uuid: Uuid,
+    phase: Phase,
+    compression: bool,
+    // Reused for every compressed packet, as creating them for every packet is expensive
+    decompressor: Decompress,
+    decompressed: Vec<u8>,
+    // Whether the backend disconnected the client itself
+    disconnected: bool,
+}
+
+// A backend setting that keeps players from joining through this proxy
+pub enum Misconfiguration {
+    // The backend asked the client to encrypt the connection
+    OnlineMode,
+    // The backend expects BungeeCord forwarding, but did not find valid forwarding data in the handshake
+    ForwardingRejected,
+    // The backend did not use the forwarded player data
+    UuidMismatch { forwarded: Uuid, received: Uuid },
+}
+
+impl std::fmt::Display for Misconfiguration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Misconfiguration::OnlineMode => write!(
+                f,
+                "it has online mode enabled, set online-mode=false in its server.properties, the proxy in front of this one already verifies players"
+            ),
+            Misconfiguration::ForwardingRejected => write!(
+                f,
+                "it expects BungeeCord forwarding but did not receive valid forwarding data, check that outbound_forwarding is \"legacy\" for it and try a different fml_marker if it is modded"
+            ),
+            Misconfiguration::UuidMismatch {
+                forwarded,
+                received,
+            } => write!(
+                f,
+                "it logged the player in as {received} instead of the forwarded {forwarded}, so it ignores the forwarded player data, make sure BungeeCord forwarding is enabled on it, like settings.bungeecord: true in its spigot.yml"
+            ),
+        }
+    }
+}
+
+// How forwarding the packets of the backend ended
+pub enum ForwardEnd {
+    BackendClosed,
+    // The packet showing the misconfiguration was not forwarded
+    Misconfigured(Misconfiguration),
+    BackendFailed(tokio::io::Error),
+    ClientFailed(tokio::io::Error),
+    // The last packet was forwarded completely
+    Cancelled,
+}
+
+impl ClientboundState {
+    pub fn new(protocol: i32, forwarded_uuid: Uuid) -> Self {
+        Self {
+            protocol,
+            forwarded_uuid,
+            phase: Phase::Login,
+            compression: false,
+            decompressor: Decompress::new(true),
+            decompressed: Vec::new(),
+            disconnected: false,
+        }
+    }
+
+    // Whether the last packet of the backend was a disconnect, meaning it closed the connection on purpose
+    pub fn backend_disconnected(&self) -> bool {
+        self.disconnected
+    }
+
+    // Forwards the packets of the backend to the client, only ever writing whole packets
+    // Cancelling only stops it between packets, so a disconnect can follow
+    pub async fn forward<R, W>(
+        &mut self,
+        backend: &mut BufReader<R>,
+        client: &mut W,
+        cancel: &CancellationToken,
+    ) -> ForwardEnd
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut frame = Vec::new();
+        loop {
+            let length = tokio::select! {
+                _ = cancel.cancelled() => return ForwardEnd::Cancelled,
+                length = VarInt::read(backend) => match length {
+                    Ok(length) => length,
+                    Err(e) if e.kind() == tokio::io::ErrorKind::UnexpectedEof => {
+                        return ForwardEnd::BackendClosed;
+                    }
+                    Err(e) => return ForwardEnd::BackendFailed(e),
+                },
+            };
+            if !(0..=MAX_PACKET_LENGTH).contains(&*length) {
+                return ForwardEnd::BackendFailed(tokio::io::Error::new(
+                    tokio::io::ErrorKind::InvalidData,
+                    format!("Packet length {} is out of range", *length),
+                ));
+            }
+
+            // A packet the backend did not finish sending is never passed on
+            frame.clear();
+            let mut packet = (&mut *backend).take(*length as u64);
+            let read = tokio::select! {
+                _ = cancel.cancelled() => return ForwardEnd::Cancelled,
+                read = packet.read_to_end(&mut frame) => read,
+            };
+            match read {
+                Ok(read) if read == *length as usize => (),
+                Ok(_) => return ForwardEnd::BackendClosed,
+                Err(e) => return ForwardEnd::BackendFailed(e),
+            }
+
+            match self.observe(&frame).await {
+                Ok(None) => (),
+                Ok(Some(misconfiguration)) => {
+                    return ForwardEnd::Misconfigured(misconfiguration);
+                }
+                Err(e) => debug!("Failed to follow a packet from the backend: {e}"),
+            }
+
+            let written = async {
+                length.write(client).await?;
+                client.write_all(&frame).await?;
+                // Send everything before reading the next packet would have to wait for the backend
+                if !holds_whole_packet(backend.buffer()).await {
+                    client.flush().await?;
+                }
+                Ok(())
+            };
+            if let Err(e) = written.await {
+                return ForwardEnd::ClientFailed(e);
+            }
+        }
+    }
+
+    // Updates the state according to a packet of the backend, the frame does not include the packet length
+    // Returns the misconfiguration of the backend the packet shows, if any
+    pub async fn observe(&mut self, frame: &[u8]) -> tokio::io::Result<Option<Misconfiguration>> {
+        let mut reader = frame;
+
+        if self.compression {
+            let data_length = VarInt::read(&mut reader).await?;
+            if *data_length != 0 {
+                // Login packets are looked into, of all others only the start is needed to know their id
+                let needed = match self.phase {
+                    Phase::Login => ((*data_length).max(0) as usize).min(MAX_DECOMPRESSED_LENGTH),
+                    Phase::Configuration | Phase::Play => 5,
+                };
+                // The buffer keeps its capacity, so it is sized to exactly what is decompressed
+                self.decompressor.reset(true);
+                self.decompressed.resize(needed, 0);
+                self.decompressor
+                    .decompress(reader, &mut self.decompressed, FlushDecompress::Finish)
+                    .map_err(|e| tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, e))?;
+                self.decompressed
+                    .truncate(self.decompressor.total_out() as usize);
+                reader = &self.decompressed;
+            }
+        }
+        let packet_id = VarInt::read(&mut reader).await?;
+
+        let version = ProtocolVersion::get(self.protocol);
+        let is = |id: Option<u8>| id.is_some_and(|id| i32::from(id) == *packet_id);
+
+        match self.phase {
+            Phase::Login => match *packet_id {
+                LOGIN_DISCONNECT => {
+                    let reason = MCString::<32767>::read(&mut reader).await?;
+                    if reason.as_str().contains(FORWARDING_REJECTED_MESSAGE) {
+                        return Ok(Some(Misconfiguration::ForwardingRejected));
+                    }
+                    self.disconnected = true;
+                }
+                ENCRYPTION_REQUEST => return Ok(Some(Misconfiguration::OnlineMode)),
+                SET_COMPRESSION if self.protocol >= COMPRESSION_PROTOCOL => {
+                    // A negative threshold turns compression off again
+                    self.compression = *VarInt::read(&mut reader).await? >= 0;
+                    trace!("Backend set compression to {}", self.compression);
+                }
+                LOGIN_SUCCESS => {
+                    let received = if self.protocol >= BINARY_UUID_PROTOCOL {
+                        Uuid::read(&mut reader).await?
+                    } else {
+                        let uuid = MCString::<36>::read(&mut reader).await?;
+                        u128::from_str_radix(&uuid.as_str().replace('-', ""), 16)
+                            .map(Uuid)
+                            .map_err(|_| {
+                                tokio::io::Error::new(
+                                    tokio::io::ErrorKind::InvalidData,
+                                    "The uuid in the login success packet is invalid",
+                                )
+                            })?
+                    };
+                    if *received != *self.forwarded_uuid {
+                        return Ok(Some(Misconfiguration::UuidMismatch {
+                            forwarded: self.forwarded_uuid,
+                            received,
+                        }));
+                    }
+
+                    self.phase = match version.and_then(|version| version.configuration.as_ref()) {
+                        Some(_) => Phase::Configuration,
+                        None => Phase::Play,
+                    };
+                }
+                _ => (),
+            },
+            Phase::Configuration => {
+                let ids = version.and_then(|version| version.configuration.as_ref());
+                if is(ids.map(|ids| ids.disconnect)) {
+                    self.disconnected = true;
+                } else if is(ids.map(|ids| ids.finish)) {
+                    self.phase = Phase::Play;
+                }
+            }
+            Phase::Play => {
+                if is(version.map(|version| version.play.disconnect)) {
+                    self.disconnected = true;
+                } else if is(version.and_then(|version| version.play.start_configuration)) {
+                    self.phase = Phase::Configuration;
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    // A complete disconnect packet the client understands in its current state
+    pub async fn disconnect_packet(
+        &self,
+        reason: &str,
+    ) -> Result<Vec<u8>, WriteVersionedPacketError> {
+        let mut packet = Vec::new();
+        match self.phase {
+            Phase::Login => packet.write_packet(&Disconnect::reason(reason)).await?,
+            Phase::Configuration => {
+                packet
+                    .write_packet_versioned(
+                        &ConfigurationDisconnect::reason(reason, self.protocol),
+                        self.protocol,
+                    )
+                    .await?
+            }
+            Phase::Play => {
+                packet
+                    .write_packet_versioned(
+                        &PlayDisconnect::reason(reason, self.protocol),
+                        self.protocol,
+                    )
+                    .await?
+            }
+        }
+
+        if !self.compression {
+            return Ok(packet);
+        }
+
+        // With compression, a data length of 0 marks a packet that is sent uncompressed
+        let mut reader = &packet[..];
+        let length = VarInt::read(&mut reader).await?;
+        let mut uncompressed = Vec::with_capacity(packet.len() + 1);
+        VarInt::new(*length + 1)
+            .unwrap()
+            .write(&mut uncompressed)
+            .await?;
+        uncompressed.push(0x00);
+        uncompressed.extend_from_slice(reader);
+        Ok(uncompressed)
+    }
+}
+
+// Whether the buffer already holds the next packet completely, including its length
+async fn holds_whole_packet(mut buffer: &[u8]) -> bool {
+    match VarInt::read(&mut buffer).await {
+        Ok(length) => buffer.len() >= (*length).max(0) as usize,
+        Err(_) => false,
+    }
+}
@@ -1,33 +1,285 @@
+use base64::{Engine, prelude::BASE64_STANDARD};
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::{
     net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
     sync::Arc,
 };
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
 use tokio::{
     fs::{File, OpenOptions},
     io::{AsyncReadExt, AsyncWriteExt},
 };
+use toml::value::Datetime;
 use toml_example::TomlExample;
-use tracing::{info, level_filters::LevelFilter, trace, warn};
+use tracing::{debug, info, level_filters::LevelFilter, trace, warn};
+
+use crate::packets::{MODERN_FORWARDING_DEFAULT, MODERN_FORWARDING_MAX_VERSION};
 
 #[derive(TomlExample, Deserialize)]
 pub struct TomlConfig {
-    /// The Address this proxy will try to listen to
-    #[toml_example(default = "0.0.0.0:45565")]
-    pub bind_address: SocketAddr,
-    /// The Address this proxy will try to forward the traffic to
-    #[toml_example(default = "127.0.0.1:35565")]
-    pub backend_address: SocketAddr,
+    #[serde(flatten)]
+    #[toml_example(flatten, nesting)]
+    pub backend: BackendConfig,
+    /// How the proxy in front of this one forwards player data, it can be one of:
+    /// "modern": Velocity modern forwarding, verified using the forwarding secret
+    /// "bungeeguard": BungeeCord forwarding, verified using the bungeeguard tokens
+    /// "bungeecord": BungeeCord forwarding without verification, this requires the trusted ips to be set
+    #[serde(default)]
+    #[toml_example(default = "modern")]
+    pub inbound_forwarding: InboundForwarding,
     /// The Velocity forwarding secret, alternatively you can set the FORWARDING_SECRET environment variable
+    #[serde(default)]
     #[toml_example(default = "")]
     pub forwarding_secret: Arc<str>,
+    /// A file containing the Velocity forwarding secret, like Velocity's forwarding.secret, this is used instead of the forwarding secret
+    /// Alternatively you can set the FORWARDING_SECRET_FILE environment variable
+    #[toml_example(default = "forwarding.secret")]
+    pub forwarding_secret_file: Option<PathBuf>,
+    /// When the forwarding secret stops being accepted from the proxy in front of this one, keep this unset to accept it forever
+    #[toml_example(default = "2030-01-01T00:00:00Z")]
+    pub forwarding_secret_not_after: Option<ConfigTimestamp>,
+    /// The ips the forwarding secret is accepted from, keep this empty to accept it from all trusted ips
+    #[serde(default)]
+    #[toml_example(default = [])]
+    pub forwarding_secret_source_ips: Vec<IpAddr>,
+    /// Additional Velocity forwarding secrets that are accepted next to the forwarding secret
+    #[toml_example(nesting)]
+    pub forwarding_secrets: Option<Vec<ForwardingSecret>>,
+    /// The highest Velocity forwarding version requested from the proxy in front of this one, from 1 to 4
+    /// Versions above 1 forward the chat signing data of newer clients
+    #[serde(default = "default_max_forwarding_version")]
+    #[toml_example(default = 4)]
+    pub max_forwarding_version: u8,
+    /// What happens when the proxy in front of this one does not understand the forwarding request, as Velocity is not modified or not using modern forwarding, it can be one of:
+    /// "disconnect": The player is disconnected with a message asking them to contact an administrator
+    /// "bungeeguard": The BungeeCord forwarding data in the handshake is used instead, verified using the bungeeguard tokens
+    /// "bungeecord": The BungeeCord forwarding data in the handshake is used instead without verification, this requires the trusted ips to be set
+    #[serde(default)]
+    #[toml_example(default = "disconnect")]
+    pub not_understood_fallback: NotUnderstoodFallback,
+    /// How many milliseconds clients have to send their handshake after connecting
+    #[serde(default = "default_handshake_timeout_ms")]
+    #[toml_example(default = 5000)]
+    pub handshake_timeout_ms: u64,
+    /// How many milliseconds clients have to send their login start packet after the handshake
+    #[serde(default = "default_login_start_timeout_ms")]
+    #[toml_example(default = 5000)]
+    pub login_start_timeout_ms: u64,
+    /// How many milliseconds the proxy in front of this one has to send its answer to a forwarding request before the connection is closed
+    #[serde(default = "default_forwarding_response_timeout_ms")]
+    #[toml_example(default = 10000)]
+    pub forwarding_response_timeout_ms: u64,
+    /// How many packets a client may send while the answer to a forwarding request is awaited
+    /// These packets are kept until the client is forwarded to the backend, so they are limited to protect the memory of this proxy
+    #[serde(default = "default_max_buffered_packets")]
+    #[toml_example(default = 64)]
+    pub max_buffered_packets: usize,
+    /// How many bytes the packets sent while the answer to a forwarding request is awaited may add up to
+    #[serde(default = "default_max_buffered_bytes")]
+    #[toml_example(default = 262144)]
+    pub max_buffered_bytes: usize,
+    /// How many bytes a single packet clients send before they are forwarded to the backend may have
+    /// This covers the handshake, so it has to fit the BungeeCord forwarding data with "bungeeguard" and "bungeecord" inbound forwarding
+    #[serde(default = "default_max_login_packet_length")]
+    #[toml_example(default = 32768)]
+    pub max_login_packet_length: usize,
+    /// Whether players transferred to this server by the transfer packet of 1.20.5 and newer are accepted
+    /// They are verified like any other login and join the backend as a normal login
+    #[serde(default)]
+    #[toml_example(default = false)]
+    pub accept_transfers: bool,
+    /// How many client connections are handled at the same time across all backends, keep this unset to not limit it
+    /// Connections above this limit are closed, logins are told that the server is too busy
+    #[toml_example(default = 1000)]
+    pub max_connections: Option<usize>,
     /// The trusted ips that are allowed to connect, keep this empty to allow all connections
     #[toml_example(default = [])]
     pub trusted_ips: Vec<IpAddr>,
     /// The logging verbosity of this proxy, it can be one of: "off", "error", "warn", "info", "debug" or "trace"
     #[toml_example(default = "info")]
     pub log_level: ConfigLevelFilter,
+    /// Additional backends, each listening on its own address and configured like the one above
+    #[toml_example(nesting)]
+    pub backends: Option<Vec<BackendConfig>>,
+}
+
+#[derive(TomlExample, Deserialize, Clone)]
+pub struct BackendConfig {
+    /// The Address this proxy will try to listen to
+    #[toml_example(default = "0.0.0.0:45565")]
+    pub bind_address: SocketAddr,
+    /// The Address this proxy will try to forward the traffic to
+    #[toml_example(default = "127.0.0.1:35565")]
+    pub backend_address: SocketAddr,
+    /// How many milliseconds connecting to the backend may take before the attempt is given up
+    #[serde(default = "default_backend_connect_timeout_ms")]
+    #[toml_example(default = 5000)]
+    pub backend_connect_timeout_ms: u64,
+    /// How often connecting to the backend is retried before players are told the server is offline, waiting longer before every retry
+    #[serde(default = "default_backend_connect_retries")]
+    #[toml_example(default = 2)]
+    pub backend_connect_retries: u32,
+    /// How player data is forwarded to the backend, it can be one of:
+    /// "legacy": BungeeCord forwarding inside the handshake
+    /// "modern": Velocity modern forwarding, signed using the backend forwarding secret
+    #[serde(default)]
+    #[toml_example(default = "legacy")]
+    pub outbound_forwarding: OutboundForwarding,
+    /// The Velocity forwarding secret of the backend for "modern" outbound forwarding, the forwarding secret is used if this is not set
+    #[toml_example(default = "")]
+    pub backend_forwarding_secret: Option<Arc<str>>,
+    /// How many milliseconds the backend has to ask for the player data with "modern" outbound forwarding, after it got the login start
+    #[serde(default = "default_forwarding_request_timeout_ms")]
+    #[toml_example(default = 10000)]
+    pub forwarding_request_timeout_ms: u64,
+    /// Where the marker Forge clients add to the handshake (like "\0FML\0") is put for the backend, it can be one of:
+    /// "properties": In the "forgeClient" and "extraData" properties like BungeeCord does, or after the address with "modern" outbound forwarding
+    /// "append": After the forwarding data, at the very end of the address
+    /// "strip": The backend does not get the marker at all
+    #[serde(default)]
+    #[toml_example(default = "properties")]
+    pub fml_marker: FmlMarkerPlacement,
+    /// How many milliseconds a status response of the backend is reused for server list pings, before the backend is asked again
+    /// Keep this at 0 to ask the backend for every ping
+    #[serde(default = "default_status_cache_ttl_ms")]
+    #[toml_example(default = 5000)]
+    pub status_cache_ttl_ms: u64,
+    /// How many milliseconds the backend has to answer a status request once the proxy is connected to it
+    #[serde(default = "default_status_read_timeout_ms")]
+    #[toml_example(default = 5000)]
+    pub status_read_timeout_ms: u64,
+    /// Replaces the description the backend shows in the server list, keep this unset to show the one of the backend
+    /// "{motd}", "{online}", "{max}" and "{version}" are replaced with the description, player counts and version name the backend sent
+    #[toml_example(default = "{motd}")]
+    pub status_motd: Option<Arc<str>>,
+    /// Replaces the version name the backend shows in the server list, "{version}" is replaced with the one the backend sent
+    #[toml_example(default = "{version}")]
+    pub status_version_name: Option<Arc<str>>,
+    /// A PNG image shown as the icon of the server in the server list instead of the one of the backend, it should be 64x64 pixels
+    #[toml_example(default = "server-icon.png")]
+    pub status_favicon: Option<PathBuf>,
+    // The favicon as sent to clients, loaded from the file above
+    #[serde(skip)]
+    #[toml_example(skip)]
+    pub status_favicon_data: Option<Arc<str>>,
+    /// Which players are listed when hovering over the player count in the server list, it can be one of:
+    /// "keep": The players the backend sent
+    /// "hide": No players
+    /// "anonymous": As many players as the backend sent, all shown as "Anonymous Player"
+    #[serde(default)]
+    #[toml_example(default = "keep")]
+    pub status_player_sample: StatusPlayerSample,
+    /// Replaces the maximum player count the backend shows in the server list
+    #[toml_example(default = 100)]
+    pub status_max_players: Option<u32>,
+    /// The highest maximum player count shown in the server list, higher counts of the backend are lowered to this
+    #[toml_example(default = 100)]
+    pub status_max_players_limit: Option<u32>,
+    /// The description shown in the server list while the backend can't be reached
+    #[serde(default = "default_offline_motd")]
+    #[toml_example(default = "The server is currently offline")]
+    pub offline_motd: Arc<str>,
+    /// The version name shown in the server list while the backend can't be reached
+    /// It is shown along with the protocol number of the client, so the server does not appear incompatible
+    #[serde(default = "default_offline_version_name")]
+    #[toml_example(default = "Offline")]
+    pub offline_version_name: Arc<str>,
+    /// The player counts shown in the server list while the backend can't be reached
+    #[serde(default)]
+    #[toml_example(default = 0)]
+    pub offline_online_players: u32,
+    #[serde(default)]
+    #[toml_example(default = 0)]
+    pub offline_max_players: u32,
+    /// Whether the last status the backend sent is shown instead while it can't be reached, if there is one
+    #[serde(default)]
+    #[toml_example(default = false)]
+    pub offline_use_last_status: bool,
+    /// The reason players are disconnected with when the connection to the backend is lost while they are playing
+    /// This lets the proxy in front of this one move them to another server
+    #[serde(default = "default_connection_lost_reason")]
+    #[toml_example(default = "Lost the connection to the server")]
+    pub connection_lost_reason: Arc<str>,
+    /// The UDP port the query protocol of server list sites and monitoring tools is answered on, at the address this proxy listens to
+    /// Keep this unset to not answer queries, vanilla servers use the same port as for players
+    #[toml_example(default = 45565)]
+    pub query_port: Option<u16>,
+    /// BungeeGuard tokens used to prove to the backend that a connection came through this proxy
+    /// Only the first token is sent, as BungeeGuard rejects handshakes containing more than one
+    /// In "bungeeguard" inbound forwarding, all of them are accepted from the proxy in front of this one
+    #[serde(default)]
+    #[toml_example(default = [])]
+    pub bungeeguard_tokens: Vec<Arc<str>>,
+}
+
+#[derive(TomlExample, Deserialize)]
+pub struct ForwardingSecret {
+    /// The Velocity forwarding secret
+    #[toml_example(default = "")]
+    pub secret: Arc<str>,
+    /// When this secret stops being accepted, keep this unset to accept it forever
+    #[toml_example(default = "2030-01-01T00:00:00Z")]
+    pub not_after: Option<ConfigTimestamp>,
+    /// The ips this secret is accepted from, keep this empty to accept it from all trusted ips
+    #[serde(default)]
+    #[toml_example(default = [])]
+    pub source_ips: Vec<IpAddr>,
+}
+
+impl ForwardingSecret {
+    fn applies_to(&self, source: IpAddr, now: OffsetDateTime) -> bool {
+        secret_applies_to(self.not_after, &self.source_ips, source, now)
+    }
+}
+
+// Whether a secret that is valid until not_after and only for the source ips is accepted from the source now
+fn secret_applies_to(
+    not_after: Option<ConfigTimestamp>,
+    source_ips: &[IpAddr],
+    source: IpAddr,
+    now: OffsetDateTime,
+) -> bool {
+    not_after.is_none_or(|not_after| now <= OffsetDateTime::from(not_after))
+        && (source_ips.is_empty() || source_ips.contains(&source))
+}
+
+// Which of the configured secrets was used, for logging
+#[derive(Clone, Copy)]
+pub enum SecretSource {
+    Primary,
+    Additional(usize),
+}
+
+impl std::fmt::Display for SecretSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretSource::Primary => write!(f, "the forwarding secret"),
+            SecretSource::Additional(index) => {
+                write!(f, "additional forwarding secret {}", index + 1)
+            }
+        }
+    }
+}
+
+impl BackendConfig {
+    // The secret used to sign the player data for "modern" outbound forwarding
+    pub fn outbound_secret<'a>(&'a self, config: &'a TomlConfig) -> &'a str {
+        self.backend_forwarding_secret
+            .as_deref()
+            .unwrap_or(&config.forwarding_secret)
+    }
+
+    // Whether any of the status rewriting rules are configured
+    pub fn rewrites_status(&self) -> bool {
+        self.status_motd.is_some()
+            || self.status_version_name.is_some()
+            || self.status_favicon_data.is_some()
+            || self.status_player_sample != StatusPlayerSample::Keep
+            || self.status_max_players.is_some()
+            || self.status_max_players_limit.is_some()
+    }
 }
 
 impl TomlConfig {
@@ -64,17 +316,74 @@ impl TomlConfig {
         trace!("Trying to parse config");
         let mut config = toml::from_str::<TomlConfig>(&contents).map_err(ConfigError::Parse)?;
 
+        if let Some(not_after) = config.forwarding_secret_not_after
+            && OffsetDateTime::from(not_after) < OffsetDateTime::now_utc()
+        {
+            warn!(
+                "The {} expired at {not_after}, it will not be accepted anymore",
+                SecretSource::Primary
+            );
+        }
+
+        let additional_secrets = config.forwarding_secrets.as_deref().unwrap_or_default();
+        for (index, secret) in additional_secrets.iter().enumerate() {
+            if secret.secret.is_empty() {
+                return Err(ConfigError::EmptyAdditionalSecret(index + 1));
+            }
+
+            if let Some(not_after) = secret.not_after
+                && OffsetDateTime::from(not_after) < OffsetDateTime::now_utc()
+            {
+                warn!(
+                    "The {} expired at {not_after}, it will not be accepted anymore",
+                    SecretSource::Additional(index)
+                );
+            }
+        }
+
+        // The additional secrets can replace the forwarding secret for inbound forwarding
+        let secret_required =
+            config.inbound_forwarding == InboundForwarding::Modern && additional_secrets.is_empty();
+
+        let secret_file = match std::env::var_os("FORWARDING_SECRET_FILE") {
+            Some(path) if !path.is_empty() => {
+                trace!("Using FORWARDING_SECRET_FILE from environment");
+                Some(PathBuf::from(path))
+            }
+            _ => config.forwarding_secret_file.clone(),
+        };
+
+        if let Some(path) = secret_file {
+            trace!("Loading forwarding secret from {}", path.display());
+            let contents = tokio::fs::read_to_string(&path)
+                .await
+                .map_err(|e| ConfigError::SecretFile(path.clone(), e))?;
+
+            if !config.forwarding_secret.is_empty() {
+                warn!(
+                    "The forwarding secret is specified in both the config and a file, using the one from the file"
+                );
+            }
+            // Editors and secret mounts commonly end files with a newline, which is not part of the secret
+            config.forwarding_secret = contents.trim_end_matches(['\r', '\n']).into();
+        }
+
         match (
             !config.forwarding_secret.is_empty(),
             std::env::var("FORWARDING_SECRET"),
         ) {
-            (false, Err(_)) => return Err(ConfigError::NoSecret),
+            (false, Err(_)) if secret_required => return Err(ConfigError::NoSecret),
+            (false, Err(_)) => {
+                trace!(
+                    "No forwarding secret configured, it is not needed for this inbound forwarding mode"
+                );
+            }
             (true, Err(_)) => {
                 trace!("Using forwarding secret from config");
                 // This requires nothing to be done, as it is already stored there
             }
             (false, Ok(secret)) => {
-                if secret.is_empty() {
+                if secret.is_empty() && secret_required {
                     return Err(ConfigError::NoSecret);
                 }
                 trace!("Using FORWARDING_SECRET from environment");
@@ -93,8 +402,259 @@ impl TomlConfig {
             }
         }
 
+        if !(MODERN_FORWARDING_DEFAULT..=MODERN_FORWARDING_MAX_VERSION)
+            .contains(&config.max_forwarding_version)
+        {
+            return Err(ConfigError::InvalidForwardingVersion(
+                config.max_forwarding_version,
+            ));
+        }
+
+        // The fallback for modern forwarding needs the same as using its forwarding directly
+        let uses_forwarding = |forwarding| {
+            config.inbound_forwarding == forwarding
+                || (config.inbound_forwarding == InboundForwarding::Modern
+                    && config.not_understood_fallback.forwarding() == Some(forwarding))
+        };
+
+        if uses_forwarding(InboundForwarding::BungeeCord) && config.trusted_ips.is_empty() {
+            return Err(ConfigError::UnverifiedForwarding);
+        }
+
+        for backend in
+            std::iter::once(&mut config.backend).chain(config.backends.iter_mut().flatten())
+        {
+            if let Some(path) = &backend.status_favicon {
+                trace!("Loading favicon from {}", path.display());
+                backend.status_favicon_data = Some(load_favicon(path).await?);
+            }
+        }
+
+        for backend in config.backends() {
+            if uses_forwarding(InboundForwarding::BungeeGuard)
+                && backend.bungeeguard_tokens.is_empty()
+            {
+                return Err(ConfigError::NoBungeeGuardToken(backend.bind_address));
+            }
+
+            if backend.outbound_forwarding == OutboundForwarding::Modern
+                && backend.outbound_secret(&config).is_empty()
+            {
+                return Err(ConfigError::NoBackendSecret(backend.bind_address));
+            }
+        }
+
         Ok(config)
     }
+
+    // Logs fingerprints of the loaded secrets, so they can be compared with the ones of the proxy in front of this one
+    pub fn log_secret_fingerprints(&self) {
+        if !self.forwarding_secret.is_empty() {
+            debug!(
+                "Loaded forwarding secret with fingerprint {}",
+                secret_fingerprint(&self.forwarding_secret)
+            );
+        }
+        for (index, secret) in self.forwarding_secrets.iter().flatten().enumerate() {
+            debug!(
+                "Loaded {} with fingerprint {}",
+                SecretSource::Additional(index),
+                secret_fingerprint(&secret.secret)
+            );
+        }
+    }
+
+    // The secrets accepted from the proxy at the given address, starting with the forwarding secret
+    pub fn inbound_secrets(&self, source: IpAddr) -> impl Iterator<Item = (SecretSource, &str)> {
+        let now = OffsetDateTime::now_utc();
+        let primary = (!self.forwarding_secret.is_empty()
+            && secret_applies_to(
+                self.forwarding_secret_not_after,
+                &self.forwarding_secret_source_ips,
+                source,
+                now,
+            ))
+        .then_some((SecretSource::Primary, &*self.forwarding_secret));
+
+        primary.into_iter().chain(
+            self.forwarding_secrets
+                .iter()
+                .flatten()
+                .enumerate()
+                .filter(move |(_, secret)| secret.applies_to(source, now))
+                .map(|(index, secret)| (SecretSource::Additional(index), &*secret.secret)),
+        )
+    }
+
+    // All configured backends, starting with the one configured at the top level
+    pub fn backends(&self) -> impl Iterator<Item = &BackendConfig> {
+        std::iter::once(&self.backend).chain(self.backends.iter().flatten())
+    }
+}
+
+// The start of the SHA-256 hash of a secret, to compare secrets without logging them
+fn secret_fingerprint(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .take(8)
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+// Prefix of every PNG file
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+// Clients only show favicons of this size correctly
+const FAVICON_SIZE: u32 = 64;
+
+// The whole status response has to fit into a single protocol string, this leaves room for the rest of it
+const MAX_FAVICON_LENGTH: usize = i16::MAX as usize - 4096;
+
+// Reads a PNG file into the data URL clients expect as the favicon of a status response
+async fn load_favicon(path: &Path) -> Result<Arc<str>, ConfigError> {
+    let image = tokio::fs::read(path)
+        .await
+        .map_err(|e| ConfigError::Favicon(path.to_path_buf(), e))?;
+
+    // The width and height are the first fields of the IHDR chunk, which always comes first
+    if image.len() < 24 || !image.starts_with(PNG_SIGNATURE) {
+        return Err(ConfigError::InvalidFavicon(
+            path.to_path_buf(),
+            "it is not a PNG image",
+        ));
+    }
+    let width = u32::from_be_bytes(image[16..20].try_into().unwrap());
+    let height = u32::from_be_bytes(image[20..24].try_into().unwrap());
+    if (width, height) != (FAVICON_SIZE, FAVICON_SIZE) {
+        warn!(
+            "The favicon \"{}\" is {width}x{height} pixels, clients expect {FAVICON_SIZE}x{FAVICON_SIZE}",
+            path.display()
+        );
+    }
+
+    let favicon = format!("data:image/png;base64,{}", BASE64_STANDARD.encode(image));
+    if favicon.len() > MAX_FAVICON_LENGTH {
+        return Err(ConfigError::InvalidFavicon(
+            path.to_path_buf(),
+            "it is too large to be sent to clients",
+        ));
+    }
+    Ok(favicon.into())
+}
+
+fn default_max_forwarding_version() -> u8 {
+    MODERN_FORWARDING_MAX_VERSION
+}
+
+fn default_forwarding_request_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_status_cache_ttl_ms() -> u64 {
+    5000
+}
+
+fn default_status_read_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_offline_motd() -> Arc<str> {
+    "The server is currently offline".into()
+}
+
+fn default_offline_version_name() -> Arc<str> {
+    "Offline".into()
+}
+
+fn default_connection_lost_reason() -> Arc<str> {
+    "Lost the connection to the server".into()
+}
+
+fn default_handshake_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_login_start_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_forwarding_response_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_max_buffered_packets() -> usize {
+    64
+}
+
+fn default_max_buffered_bytes() -> usize {
+    256 * 1024
+}
+
+fn default_max_login_packet_length() -> usize {
+    32 * 1024
+}
+
+fn default_backend_connect_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_backend_connect_retries() -> u32 {
+    2
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InboundForwarding {
+    #[default]
+    Modern,
+    BungeeGuard,
+    BungeeCord,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotUnderstoodFallback {
+    #[default]
+    Disconnect,
+    BungeeGuard,
+    BungeeCord,
+}
+
+impl NotUnderstoodFallback {
+    // The forwarding used instead of modern forwarding, if any
+    pub fn forwarding(self) -> Option<InboundForwarding> {
+        match self {
+            NotUnderstoodFallback::Disconnect => None,
+            NotUnderstoodFallback::BungeeGuard => Some(InboundForwarding::BungeeGuard),
+            NotUnderstoodFallback::BungeeCord => Some(InboundForwarding::BungeeCord),
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FmlMarkerPlacement {
+    #[default]
+    Properties,
+    Append,
+    Strip,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatusPlayerSample {
+    #[default]
+    Keep,
+    Hide,
+    Anonymous,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutboundForwarding {
+    #[default]
+    Legacy,
+    Modern,
 }
 
 pub enum ConfigError {
@@ -103,6 +663,14 @@ pub enum ConfigError {
     Write(tokio::io::Error),
     Parse(toml::de::Error),
     NoSecret,
+    SecretFile(PathBuf, tokio::io::Error),
+    EmptyAdditionalSecret(usize),
+    NoBungeeGuardToken(SocketAddr),
+    NoBackendSecret(SocketAddr),
+    UnverifiedForwarding,
+    InvalidForwardingVersion(u8),
+    Favicon(PathBuf, tokio::io::Error),
+    InvalidFavicon(PathBuf, &'static str),
     CreatedNew(PathBuf),
 }
 
@@ -115,7 +683,42 @@ impl std::fmt::Display for ConfigError {
             ConfigError::Parse(e) => write!(f, "Failed while parsing config file: {e}"),
             ConfigError::NoSecret => write!(
                 f,
-                "No forwarding secret provided, please set it in the config or in the FORWARDING_SECRET environment variable"
+                "No forwarding secret provided, please set it in the config or in the FORWARDING_SECRET or FORWARDING_SECRET_FILE environment variable"
+            ),
+            ConfigError::SecretFile(path, e) => write!(
+                f,
+                "Failed while reading forwarding secret file \"{}\": {e}",
+                path.display()
+            ),
+            ConfigError::EmptyAdditionalSecret(number) => write!(
+                f,
+                "Additional forwarding secret {number} is empty, please set its secret or remove it"
+            ),
+            ConfigError::NoBungeeGuardToken(bind_address) => write!(
+                f,
+                "No BungeeGuard token provided for the backend at {bind_address}, please set at least one in the config to accept \"bungeeguard\" forwarding"
+            ),
+            ConfigError::NoBackendSecret(bind_address) => write!(
+                f,
+                "No forwarding secret provided for the backend at {bind_address}, please set the backend forwarding secret or the forwarding secret to use \"modern\" outbound forwarding"
+            ),
+            ConfigError::UnverifiedForwarding => write!(
+                f,
+                "\"bungeecord\" forwarding can't be verified, please set the trusted ips to the addresses of your BungeeCord proxies"
+            ),
+            ConfigError::InvalidForwardingVersion(version) => write!(
+                f,
+                "The max forwarding version {version} is not supported, it has to be between {MODERN_FORWARDING_DEFAULT} and {MODERN_FORWARDING_MAX_VERSION}"
+            ),
+            ConfigError::Favicon(path, e) => write!(
+                f,
+                "Failed while reading favicon \"{}\": {e}",
+                path.display()
+            ),
+            ConfigError::InvalidFavicon(path, reason) => write!(
+                f,
+                "The favicon \"{}\" can't be used, as {reason}",
+                path.display()
             ),
             ConfigError::CreatedNew(path) => write!(
                 f,
@@ -128,7 +731,65 @@ impl std::fmt::Display for ConfigError {
     }
 }
 
+// A Wrapper around OffsetDateTime for deserializing both TOML datetimes and RFC 3339 strings
+#[derive(Clone, Copy)]
+pub struct ConfigTimestamp(OffsetDateTime);
+
+impl<'de> Deserialize<'de> for ConfigTimestamp {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct TimestampVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for TimestampVisitor {
+            type Value = ConfigTimestamp;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a datetime with an offset, like 2030-01-01T00:00:00Z")
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<ConfigTimestamp, E>
+            where
+                E: serde::de::Error,
+            {
+                OffsetDateTime::parse(value, &Rfc3339)
+                    .map(ConfigTimestamp)
+                    .map_err(|_| {
+                        serde::de::Error::invalid_value(serde::de::Unexpected::Str(value), &self)
+                    })
+            }
+
+            fn visit_map<A>(self, map: A) -> Result<ConfigTimestamp, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                // Unquoted TOML datetimes are passed as a special map
+                let datetime =
+                    Datetime::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+                self.visit_str(&datetime.to_string())
+            }
+        }
+
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
+impl std::fmt::Display for ConfigTimestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let formatted = self.0.format(&Rfc3339).map_err(|_| std::fmt::Error)?;
+        f.write_str(&formatted)
+    }
+}
+
+impl From<ConfigTimestamp> for OffsetDateTime {
+    fn from(timestamp: ConfigTimestamp) -> Self {
+        timestamp.0
+    }
+}
+
 // A Wrapper around LevelFilter for deserializing
+#[derive(Clone, Copy)]
 pub enum ConfigLevelFilter {
     Off,
     Error,
@@ -1,23 +1,52 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tokio::{
+    io::{AsyncWriteExt, BufReader, BufWriter},
+    net::TcpStream,
+};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, trace, warn};
 
 use crate::{
+    clientbound::{ClientboundState, ForwardEnd, Misconfiguration},
+    config::{
+        BackendConfig, FmlMarkerPlacement, InboundForwarding, OutboundForwarding, TomlConfig,
+    },
+    online_players::OnlinePlayers,
     packets::{
-        Disconnect, GenericPacket, Handshake, LoginStart, PlayDisconnect,
-        VelocityLoginPluginRequest, VelocityLoginPluginResponse,
-        packet_read::{ReadPacketError, ReadPacketExt},
-        packet_write::{WritePacketExt, WriteVersionedPacketError, WriteVersionedPacketExt},
+        BUNGEEGUARD_TOKEN_PROPERTY, BungeeForwardingData, Disconnect, GenericPacket, Handshake,
+        LegacyPingResponse, LoginPluginRequest, LoginStart, MODERN_FORWARDING_DEFAULT,
+        MODERN_FORWARDING_WITH_KEY, MODERN_FORWARDING_WITH_KEY_V2, MODERN_LAZY_SESSION, Packet,
+        Ping, PlayerInfo, StatusRequest, StatusResponse, VELOCITY_PLAYER_INFO_CHANNEL,
+        VelocityLoginPluginAnswer, VelocityLoginPluginRequest, VelocityLoginPluginResponse,
+        id::Managed,
+        is_legacy_ping,
+        packet_read::{MAX_PACKET_LENGTH, ReadPacket, ReadPacketError, ReadPacketExt},
+        packet_write::{WritePacketExt, WriteVersionedPacketError},
+        protocol::{ProtocolVersion, VersionName},
+        read_legacy_ping,
     },
-    types::NextState,
+    status_cache::StatusCache,
+    status_rewrite::rewrite_status,
+    types::{MCString, NextState, VarInt},
 };
 
+// Clients over the connection limit only get this long to send their handshake, as they are only told to come back later
+const OVER_LIMIT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How long to wait before retrying to connect to the backend, this doubles with every retry
+const CONNECT_RETRY_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF_DOUBLINGS: u32 = 4;
+
+// The status of the backend is requested as a 1.7.2 client for legacy pings and queries, the oldest version using modern pings
+const UNVERSIONED_STATUS_PROTOCOL: i32 = 4;
+
+// The reason players are disconnected with when the backend is not set up to be joined through this proxy
+const MISCONFIGURED_REASON: &str =
+    "This server is not set up correctly, please contact an administrator";
+
 pub struct Connection {
     client: TcpStream,
-    backend: TcpStream,
-    connection_id: i32,
 }
 
 impl Connection {
@@ -30,197 +59,968 @@ impl Connection {
         Ok(ParitalConnection { client })
     }
 
-    pub async fn handle(&mut self, secret: Arc<str>, cancel: CancellationToken) {
+    pub async fn handle(
+        &mut self,
+        config: Arc<TomlConfig>,
+        backend: Arc<BackendConfig>,
+        status_cache: Arc<StatusCache>,
+        online_players: Arc<OnlinePlayers>,
+        cancel: CancellationToken,
+    ) {
+        // Clients before 1.7 don't send a handshake to ping the server
+        match starts_with_legacy_ping(
+            &self.client,
+            Duration::from_millis(config.handshake_timeout_ms),
+        )
+        .await
+        {
+            Ok(true) => {
+                debug!("Client is requesting status with a legacy ping");
+                self.answer_legacy_ping(&config, &backend, &status_cache)
+                    .await;
+                trace!("Connection closed");
+                return;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                error!("Failed to read handshake from client: {e}");
+                return;
+            }
+        }
+
         // First, read the handshake from the client
-        let Ok(mut handshake) = self
-            .client
-            .read_packet::<Handshake>()
-            .await
-            .map_err(|e| match e {
-                ReadPacketError::Io(error) => {
-                    error!("Failed to read handshake from client: {error}",);
-                }
-                ReadPacketError::InvalidPacketId { got, .. } => {
-                    warn!("Client sent invalid packet id {got:x} for handshake");
-                }
-                ReadPacketError::PacketSizeMismatch { .. } => {
-                    warn!("Client sent handshake with invalid length");
-                }
-            })
-        else {
+        let Ok(handshake) = read_packet_within::<Handshake>(
+            &mut self.client,
+            Duration::from_millis(config.handshake_timeout_ms),
+            config.max_login_packet_length,
+        )
+        .await
+        .map_err(|e| match e {
+            ReadPacketError::Io(error) => {
+                error!("Failed to read handshake from client: {error}",);
+            }
+            ReadPacketError::InvalidPacketId { got, .. } => {
+                warn!("Client sent invalid packet id {got:x} for handshake");
+            }
+            ReadPacketError::PacketSizeMismatch { .. } => {
+                warn!("Client sent handshake with invalid length");
+            }
+        }) else {
             return;
         };
 
-        let protocol = *handshake.protocol_version;
-
         match handshake.next_state {
             NextState::Status => {
                 trace!("Client is requesting status");
-                self.forward_status(&handshake).await;
+                self.answer_status(&handshake, &config, &backend, &status_cache)
+                    .await;
             }
             NextState::Login => {
                 trace!("Client is requesting login");
-                info!(
-                    "Client from is attempting to log in with protocol: {}",
-                    *handshake.protocol_version
-                );
+                self.handle_login(handshake, config, backend, online_players, cancel)
+                    .await;
+            }
+            NextState::Transfer if config.accept_transfers => {
+                trace!("Client is requesting transfer");
+                self.handle_login(handshake, config, backend, online_players, cancel)
+                    .await;
+            }
+            NextState::Transfer => {
+                warn!("Refusing transfer, as transfers are not accepted");
+                self.disconnect("Transfers are disabled on this server")
+                    .await;
+            }
+        }
 
-                // Read the Login Start Packet
-                let Ok(mut login_start) = self.client.read_packet::<LoginStart>().await else {
-                    error!("Failed to read login start packet from client");
-                    return;
-                };
+        trace!("Connection closed");
+    }
 
-                trace!("Sending login plugin request to proxy");
-                if let Err(e) = self
-                    .client
-                    .write_packet(&VelocityLoginPluginRequest::new(self.connection_id))
+    // Verifies the player and forwards them to the backend, for both logins and transfers
+    async fn handle_login(
+        &mut self,
+        mut handshake: Handshake,
+        config: Arc<TomlConfig>,
+        backend: Arc<BackendConfig>,
+        online_players: Arc<OnlinePlayers>,
+        cancel: CancellationToken,
+    ) {
+        let protocol = *handshake.protocol_version;
+
+        info!(
+            "Client from is attempting to log in with {}",
+            VersionName(*handshake.protocol_version)
+        );
+
+        // Read the Login Start Packet
+        let mut login_start = match read_packet_within::<LoginStart>(
+            &mut self.client,
+            Duration::from_millis(config.login_start_timeout_ms),
+            config.max_login_packet_length,
+        )
+        .await
+        {
+            Ok(login_start) => login_start,
+            Err(ReadPacketError::Io(e)) => {
+                error!("Failed to read login start packet from client: {e}");
+                return;
+            }
+            Err(_) => {
+                error!("Failed to read login start packet from client");
+                return;
+            }
+        };
+
+        // Forge clients going through Velocity have their marker after the address
+        let host_fml_marker = handshake.take_fml_marker();
+
+        let verified = match config.inbound_forwarding {
+            InboundForwarding::Modern => match self.verify_modern_forwarding(&config).await {
+                Ok(player) => Some(player),
+                Err(ModernForwardingError::Unverified) => None,
+                Err(ModernForwardingError::NotUnderstood { buffer }) => {
+                    self.fall_back_from_modern_forwarding(
+                        &mut handshake,
+                        &login_start,
+                        &config,
+                        &backend,
+                        buffer,
+                    )
                     .await
+                }
+            },
+            forwarding => {
+                self.verify_bungeecord_forwarding(
+                    &mut handshake,
+                    &login_start,
+                    forwarding,
+                    &backend,
+                )
+                .await
+            }
+        };
+        let Some(player) = verified else {
+            return;
+        };
+        trace!("Forwarding data was valid, continuing with the backend login");
+
+        let VerifiedPlayer {
+            info,
+            buffer,
+            fml_marker,
+        } = player;
+        login_start.username = info.username.clone();
+
+        let fml_marker = host_fml_marker.or(fml_marker);
+        if let Some(marker) = &fml_marker {
+            debug!(
+                "Client is using Forge with the marker {}",
+                marker.replace('\0', "\\0")
+            );
+        }
+
+        match (backend.outbound_forwarding, backend.fml_marker) {
+            (OutboundForwarding::Legacy, fml_placement) => {
+                let mut properties = info.properties.clone();
+                if let (Some(marker), FmlMarkerPlacement::Properties) = (&fml_marker, fml_placement)
                 {
-                    warn!("Failed to send login plugin request to proxy: {e}");
-                    return;
-                };
+                    properties.extend(Handshake::fml_marker_properties(marker));
+                }
 
-                trace!("Waiting for login plugin response from proxy");
-                let (buffer, response) = match self.buffer_until_response().await {
-                    Ok((buffer, response)) => (buffer, response),
-                    Err(e) => {
-                        error!("Failed to read login plugin response from client: {e}");
-                        return;
-                    }
+                // Sending modified Handshake
+                let forwarding_data = BungeeForwardingData {
+                    server_address: handshake.server_address.clone(),
+                    client_address: info.client_address.clone(),
+                    player_uuid: info.player_uuid,
+                    properties,
+                    fml_marker: fml_marker.filter(|_| fml_placement == FmlMarkerPlacement::Append),
                 };
-                trace!("Received login plugin response from client");
-
-                // Validate the response
-                trace!("Validating login plugin response from proxy");
-                if *response.connection_id != self.connection_id {
-                    warn!("Client sent invalid connection id in login plugin response");
+                if let Err(e) = handshake.insert_forwarding_data(
+                    &forwarding_data,
+                    backend.bungeeguard_tokens.first().map(|token| &**token),
+                ) {
+                    warn!("Failed to add the forwarding data to the handshake: {e}");
                     return;
                 }
-
-                let is_valid = response.validate(&secret);
-                if !is_valid {
-                    warn!("Client sent invalid signature in login plugin response");
-
-                    if let Err(e) = self
-                        .client
-                        .write_packet(&Disconnect::reason(
-                            "Failed to verify your identity, please rejoin the server",
-                        ))
-                        .await
-                    {
-                        warn!("Failed to send disconnect packet to client");
-                        debug!("Error: {e}");
-                    }
-
+            }
+            (OutboundForwarding::Modern, FmlMarkerPlacement::Strip) => (),
+            (OutboundForwarding::Modern, _) => {
+                // The address is forwarded unchanged, so the marker goes back where the client put it
+                if let Some(marker) = &fml_marker
+                    && let Err(e) = handshake.append_fml_marker(marker)
+                {
+                    warn!("Failed to add the Forge marker to the handshake: {e}");
                     return;
                 }
-                trace!("Forwarding data was valid, continuing with modified handshake");
+            }
+        }
 
-                // Sending modified Handshake
-                handshake
-                    .insert_forwarding_data(
-                        response.client_address,
-                        response.player_uuid,
-                        &response.properties,
-                    )
-                    .await;
+        // The transfer was already accepted here, and the backend may not know about transfers at all
+        handshake.next_state = NextState::Login;
 
-                login_start.username = response.username;
+        // Only now that the player is verified, the backend gets to know about them
+        let Some(mut backend_connection) = Self::connect_backend(&backend).await else {
+            self.disconnect("The server you are trying to join is offline, please try again later")
+                .await;
+            return;
+        };
 
-                if let Err(e) = self.backend.write_packet(&handshake).await {
-                    warn!("Failed to forward handshake to backend: {e}");
-                    return;
+        if let Err(e) = backend_connection.write_packet(&handshake).await {
+            warn!("Failed to forward handshake to backend: {e}");
+            return;
+        }
+
+        if let Err(e) = backend_connection.write_packet(&login_start).await {
+            warn!("Failed to forward login start to backend: {e}");
+            return;
+        }
+
+        let mut clientbound = ClientboundState::new(protocol, info.player_uuid);
+
+        if backend.outbound_forwarding == OutboundForwarding::Modern
+            && !self
+                .answer_forwarding_request(
+                    &mut backend_connection,
+                    &mut clientbound,
+                    info,
+                    &backend,
+                    backend.outbound_secret(&config),
+                )
+                .await
+        {
+            return;
+        }
+
+        // Forward the buffered packets
+        trace!("Forwarding {} buffered packets to backend", buffer.len());
+        for packet in &buffer {
+            trace!("Forwarding buffered packet with id {:x}", packet.data[0]);
+            if let Err(e) = backend_connection.write_packet(packet).await {
+                warn!("Failed to forward buffered packet to backend: {e}");
+                return;
+            }
+        }
+
+        info!("Client authenticated successfully, now forwarding...");
+        // Listed for queries until the connection is closed
+        let _online_player = online_players.join(login_start.username.as_str());
+        self.forward_connection(&mut backend_connection, clientbound, &backend, cancel)
+            .await;
+        info!("Client disconnected");
+    }
+
+    async fn verify_modern_forwarding(
+        &mut self,
+        config: &TomlConfig,
+    ) -> Result<VerifiedPlayer, ModernForwardingError> {
+        // Message ids are VarInts, only non-negative ones are used to keep them small and valid
+        let message_id = match getrandom::u32() {
+            Ok(message_id) => (message_id >> 1) as i32,
+            Err(e) => {
+                error!("Failed to generate a login plugin message id: {e}");
+                return Err(ModernForwardingError::Unverified);
+            }
+        };
+
+        trace!("Sending login plugin request to proxy");
+        if let Err(e) = self
+            .client
+            .write_packet(&VelocityLoginPluginRequest::new(
+                message_id,
+                config.max_forwarding_version,
+            ))
+            .await
+        {
+            warn!("Failed to send login plugin request to proxy: {e}");
+            return Err(ModernForwardingError::Unverified);
+        };
+
+        trace!("Waiting for login plugin response from proxy");
+        let timeout = Duration::from_millis(config.forwarding_response_timeout_ms);
+        let (buffer, answer) =
+            match tokio::time::timeout(timeout, self.buffer_until_response(config))
+                .await
+                .unwrap_or_else(|_| Err(timed_out(timeout)))
+            {
+                Ok((buffer, answer)) => (buffer, answer),
+                Err(e) => {
+                    error!("Failed to read login plugin response from client: {e}");
+                    return Err(ModernForwardingError::Unverified);
                 }
+            };
+        trace!("Received login plugin response from client");
 
-                if let Err(e) = self.backend.write_packet(&login_start).await {
-                    warn!("Failed to forward login start to backend: {e}");
-                    return;
+        // Validate the response
+        trace!("Validating login plugin response from proxy");
+        // The signature does not cover the message id, so this can't tell a captured response from a new one
+        if *answer.connection_id() != message_id {
+            warn!("Client sent invalid message id in login plugin response");
+            return Err(ModernForwardingError::Unverified);
+        }
+
+        let response = match answer {
+            VelocityLoginPluginAnswer::Forwarded(response) => *response,
+            VelocityLoginPluginAnswer::NotUnderstood { .. } => {
+                return Err(ModernForwardingError::NotUnderstood { buffer });
+            }
+        };
+
+        let source = match self.client.peer_addr() {
+            Ok(address) => address.ip(),
+            Err(e) => {
+                error!("Failed to get the address of the client: {e}");
+                return Err(ModernForwardingError::Unverified);
+            }
+        };
+        let Some(secret) = response.validate(config.inbound_secrets(source)) else {
+            warn!("Client sent invalid signature in login plugin response");
+            self.disconnect_unverified().await;
+            return Err(ModernForwardingError::Unverified);
+        };
+        debug!("Login plugin response was signed with {secret}");
+
+        if *response.player_info.version > config.max_forwarding_version as i32 {
+            warn!(
+                "Client sent forwarding version {} in login plugin response, but at most {} was requested",
+                *response.player_info.version, config.max_forwarding_version
+            );
+            self.disconnect_unverified().await;
+            return Err(ModernForwardingError::Unverified);
+        }
+        debug!(
+            "Received forwarding version {}",
+            *response.player_info.version
+        );
+
+        Ok(VerifiedPlayer {
+            info: response.player_info,
+            buffer,
+            fml_marker: None,
+        })
+    }
+
+    async fn verify_bungeecord_forwarding(
+        &mut self,
+        handshake: &mut Handshake,
+        login_start: &LoginStart,
+        forwarding: InboundForwarding,
+        backend: &BackendConfig,
+    ) -> Option<VerifiedPlayer> {
+        trace!("Reading BungeeCord forwarding data from handshake");
+        let mut forwarding_data = match handshake.extract_forwarding_data() {
+            Ok(forwarding_data) => forwarding_data,
+            Err(e) => {
+                warn!("Client sent invalid BungeeCord forwarding data: {e}");
+                self.disconnect_unverified().await;
+                return None;
+            }
+        };
+
+        let mut tokens = forwarding_data
+            .properties
+            .iter()
+            .filter(|property| property.name.as_str() == BUNGEEGUARD_TOKEN_PROPERTY);
+
+        let is_valid = match (tokens.next(), tokens.next()) {
+            // Plain BungeeCord forwarding is only protected by the trusted ips
+            _ if forwarding == InboundForwarding::BungeeCord => true,
+            (Some(token), None) => {
+                // Every token is compared, so the time taken does not tell which one matched
+                let token = token.value.as_str().as_bytes();
+                let is_accepted = backend
+                    .bungeeguard_tokens
+                    .iter()
+                    .fold(false, |found, accepted| {
+                        found | constant_time_eq(accepted.as_bytes(), token)
+                    });
+                if !is_accepted {
+                    warn!("Client sent invalid BungeeGuard token in forwarding data");
                 }
+                is_accepted
+            }
+            (None, _) => {
+                warn!("Client sent no BungeeGuard token in forwarding data");
+                false
+            }
+            (Some(_), Some(_)) => {
+                warn!("Client sent more than one BungeeGuard token in forwarding data");
+                false
+            }
+        };
 
-                // Forward the buffered packets
-                trace!("Forwarding {} buffered packets to backend", buffer.len());
-                for packet in &buffer {
-                    trace!("Forwarding buffered packet with id {:x}", packet.data[0]);
-                    if let Err(e) = self.backend.write_packet(packet).await {
-                        warn!("Failed to forward buffered packet to backend: {e}");
-                        return;
+        if !is_valid {
+            self.disconnect_unverified().await;
+            return None;
+        }
+
+        // The token must not end up in the player data forwarded to the backend
+        forwarding_data
+            .properties
+            .retain(|property| property.name.as_str() != BUNGEEGUARD_TOKEN_PROPERTY);
+
+        // Only keep the original address, the forwarding data gets inserted again for the backend
+        handshake.server_address = forwarding_data.server_address;
+
+        // Newer clients send their chat signing key and uuid in the login start, like Velocity forwards them
+        let fields = match login_start.fields(*handshake.protocol_version).await {
+            Ok(fields) => fields,
+            Err(e) => {
+                warn!("Client sent invalid login start packet: {e}");
+                self.disconnect_unverified().await;
+                return None;
+            }
+        };
+        let version = match (&fields.player_key, fields.player_uuid) {
+            (Some(_), Some(_)) => MODERN_FORWARDING_WITH_KEY_V2,
+            (Some(_), None) => MODERN_FORWARDING_WITH_KEY,
+            (None, _)
+                if ProtocolVersion::get(*handshake.protocol_version)
+                    .is_some_and(|version| version.login_start.uses_chat_sessions()) =>
+            {
+                MODERN_LAZY_SESSION
+            }
+            (None, _) => MODERN_FORWARDING_DEFAULT,
+        };
+
+        Some(VerifiedPlayer {
+            info: PlayerInfo {
+                version: VarInt::new(version as i32).unwrap(),
+                client_address: forwarding_data.client_address,
+                player_uuid: forwarding_data.player_uuid,
+                username: login_start.username.clone(),
+                properties: forwarding_data.properties,
+                signer_uuid: fields.player_uuid.filter(|_| fields.player_key.is_some()),
+                player_key: fields.player_key,
+            },
+            buffer: Vec::new(),
+            fml_marker: forwarding_data.fml_marker,
+        })
+    }
+
+    // Handles a proxy that did not understand the forwarding request according to the configured fallback
+    async fn fall_back_from_modern_forwarding(
+        &mut self,
+        handshake: &mut Handshake,
+        login_start: &LoginStart,
+        config: &TomlConfig,
+        backend: &BackendConfig,
+        buffer: Vec<GenericPacket>,
+    ) -> Option<VerifiedPlayer> {
+        let Some(forwarding) = config.not_understood_fallback.forwarding() else {
+            error!(
+                "The proxy in front of this one did not understand the forwarding request, make sure Velocity uses \"modern\" player info forwarding and is modified to work with this proxy"
+            );
+            self.disconnect(
+                "This server is not set up correctly to verify your identity, please contact an administrator",
+            )
+            .await;
+            return None;
+        };
+
+        debug!(
+            "The proxy in front of this one did not understand the forwarding request, falling back to the BungeeCord forwarding data in the handshake"
+        );
+        let mut player = self
+            .verify_bungeecord_forwarding(handshake, login_start, forwarding, backend)
+            .await?;
+        player.buffer = buffer;
+        Some(player)
+    }
+
+    // Answers the velocity:player_info request of a backend using modern forwarding, returns false if the login can't continue
+    async fn answer_forwarding_request(
+        &mut self,
+        backend_connection: &mut TcpStream,
+        clientbound: &mut ClientboundState,
+        info: PlayerInfo,
+        backend: &BackendConfig,
+        secret: &str,
+    ) -> bool {
+        trace!("Waiting for login plugin request from backend");
+        loop {
+            let request = match read_packet_within::<LoginPluginRequest>(
+                backend_connection,
+                Duration::from_millis(backend.forwarding_request_timeout_ms),
+                MAX_PACKET_LENGTH as usize,
+            )
+            .await
+            {
+                Ok(request) => request,
+                Err(e) => match e {
+                    ReadPacketError::Io(error) => {
+                        error!("Failed to read login plugin request from backend: {error}");
+                        return false;
                     }
-                }
+                    ReadPacketError::InvalidPacketId { got, packet, .. } => {
+                        match clientbound.observe(&packet.data).await {
+                            Ok(Some(misconfiguration)) => {
+                                self.reject_misconfigured(misconfiguration, backend).await;
+                                return false;
+                            }
+                            Ok(None) => (),
+                            Err(e) => debug!("Failed to follow a packet from the backend: {e}"),
+                        }
 
-                info!("Client authenticated successfully, now forwarding...");
-                self.forward_connection(
-                    cancel,
-                    PlayDisconnect::reason("The Proxy is shutting down"),
-                    protocol,
-                )
-                .await;
-                info!("Client disconnected");
+                        warn!(
+                            "Backend sent packet with id {got:x} instead of requesting forwarding data, make sure it has Velocity modern forwarding enabled"
+                        );
+                        // Let the client deal with whatever the backend sent
+                        if let Err(e) = self.client.write_packet(&packet).await {
+                            warn!("Failed to forward packet from backend to client: {e}");
+                            return false;
+                        }
+                        return true;
+                    }
+                    ReadPacketError::PacketSizeMismatch { .. } => {
+                        warn!("Backend sent login plugin request with invalid length");
+                        return false;
+                    }
+                },
+            };
+
+            if request.channel.as_str() != VELOCITY_PLAYER_INFO_CHANNEL {
+                trace!(
+                    "Forwarding login plugin request on channel {} to client",
+                    request.channel
+                );
+                if let Err(e) = self.client.write_packet(&request).await {
+                    warn!("Failed to forward login plugin request to client: {e}");
+                    return false;
+                }
+                continue;
             }
-            NextState::Transfer => {
-                trace!("Client is requesting transfer");
-                return;
+
+            // Velocity also assumes the default version if the backend did not request a specific one
+            let requested_version = match request.data[..] {
+                [version] => version,
+                _ => MODERN_FORWARDING_DEFAULT,
+            };
+            let info = info.for_requested_version(requested_version);
+            debug!(
+                "Backend requested forwarding version {requested_version}, answering with version {}",
+                *info.version
+            );
+
+            let response =
+                match VelocityLoginPluginResponse::signed(request.message_id, info, secret).await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        error!("Failed to sign forwarding data for backend: {e}");
+                        return false;
+                    }
+                };
+
+            if let Err(e) = backend_connection.write_packet(&response).await {
+                warn!("Failed to send login plugin response to backend: {e}");
+                return false;
             }
+
+            trace!("Answered login plugin request from backend");
+            return true;
         }
+    }
 
-        trace!("Connection closed");
+    // Tells the player that the backend can't be joined through this proxy, as it is not set up for it
+    async fn reject_misconfigured(
+        &mut self,
+        misconfiguration: Misconfiguration,
+        backend: &BackendConfig,
+    ) {
+        error!(
+            "The backend at {} is misconfigured, {misconfiguration}",
+            backend.backend_address
+        );
+        self.disconnect(MISCONFIGURED_REASON).await;
+    }
+
+    async fn disconnect_unverified(&mut self) {
+        self.disconnect("Failed to verify your identity, please rejoin the server")
+            .await;
+    }
+
+    async fn disconnect(&mut self, reason: &str) {
+        if let Err(e) = self.client.write_packet(&Disconnect::reason(reason)).await {
+            warn!("Failed to send disconnect packet to client");
+            debug!("Error: {e}");
+        }
     }
 
     async fn forward_connection(
         &mut self,
+        backend_connection: &mut TcpStream,
+        mut clientbound: ClientboundState,
+        backend: &BackendConfig,
         cancel: CancellationToken,
-        disconnect_packet: PlayDisconnect,
-        protocol_version: i32,
     ) {
-        tokio::select! {
-            result = tokio::io::copy_bidirectional(&mut self.client, &mut self.backend) => {
+        let (mut client_reader, client_writer) = self.client.split();
+        let (backend_reader, mut backend_writer) = backend_connection.split();
+        let mut client_writer = BufWriter::new(client_writer);
+        let mut backend_reader = BufReader::new(backend_reader);
+
+        // Packets from the backend are forwarded one by one, so a disconnect can be sent at any point
+        let reason = tokio::select! {
+            result = tokio::io::copy(&mut client_reader, &mut backend_writer) => {
                 match result {
-                    Ok((from_client, from_backend)) => {
-                        trace!("Connection closed, forwarded {from_client} bytes from client and {from_backend} bytes from backend");
+                    Ok(from_client) => {
+                        trace!("Connection closed by client, forwarded {from_client} bytes from client");
                     }
                     Err(e) => {
                         error!("Failed while forwarding normal server-client interaction: {e}");
                     }
                 }
+                return;
             }
-            _ = cancel.cancelled() => {
-                trace!("Shutting down active connection");
-                match self.client.write_packet_versioned(&disconnect_packet, protocol_version).await
-                {
-                    Ok(()) => trace!("Sent disconnect packet to client"),
-                    Err(e) => match e {
-                        WriteVersionedPacketError::Io(error) =>  error!("Failed while forwarding normal server-client interaction: {error}"),
-                        WriteVersionedPacketError::InvalidPacketId { protocol } => {
-                            warn!("Could not resolve disconnect packet id for protocol verison {protocol}")
-                        },
-                    },
-                };
-            },
+            end = clientbound.forward(&mut backend_reader, &mut client_writer, &cancel) => {
+                match end {
+                    ForwardEnd::BackendClosed if clientbound.backend_disconnected() => {
+                        trace!("Connection closed by backend after disconnecting the client");
+                        client_writer.flush().await.ok();
+                        return;
+                    }
+                    ForwardEnd::Misconfigured(misconfiguration) => {
+                        error!(
+                            "The backend at {} is misconfigured, {misconfiguration}",
+                            backend.backend_address
+                        );
+                        MISCONFIGURED_REASON
+                    }
+                    ForwardEnd::BackendClosed => {
+                        warn!("Backend closed the connection unexpectedly");
+                        &*backend.connection_lost_reason
+                    }
+                    ForwardEnd::BackendFailed(e) => {
+                        warn!("Lost the connection to the backend: {e}");
+                        &*backend.connection_lost_reason
+                    }
+                    ForwardEnd::ClientFailed(e) => {
+                        error!("Failed while forwarding normal server-client interaction: {e}");
+                        return;
+                    }
+                    ForwardEnd::Cancelled => {
+                        trace!("Shutting down active connection");
+                        "The Proxy is shutting down"
+                    }
+                }
+            }
+        };
+
+        let packet = match clientbound.disconnect_packet(reason).await {
+            Ok(packet) => packet,
+            Err(WriteVersionedPacketError::Io(e)) => {
+                error!("Failed to create disconnect packet: {e}");
+                return;
+            }
+            Err(WriteVersionedPacketError::InvalidPacketId { protocol }) => {
+                warn!(
+                    "Could not resolve disconnect packet id for {}",
+                    VersionName(protocol)
+                );
+                return;
+            }
+        };
+
+        // Whole packets that were already forwarded are still in the buffer, so the disconnect comes after them
+        match async {
+            client_writer.write_all(&packet).await?;
+            client_writer.flush().await
+        }
+        .await
+        {
+            Ok(()) => trace!("Sent disconnect packet to client"),
+            Err(e) => {
+                warn!("Failed to send disconnect packet to client");
+                debug!("Error: {e}");
+            }
+        }
+    }
+
+    // Answers a status request with the status of the backend, which is only asked when the cached one is outdated
+    async fn answer_status(
+        &mut self,
+        handshake: &Handshake,
+        config: &TomlConfig,
+        backend: &BackendConfig,
+        status_cache: &StatusCache,
+    ) {
+        if let Err(e) = read_packet_within::<StatusRequest>(
+            &mut self.client,
+            Duration::from_millis(config.handshake_timeout_ms),
+            config.max_login_packet_length,
+        )
+        .await
+        {
+            if let ReadPacketError::Io(error) = e {
+                debug!("Failed to read status request from client: {error}");
+            } else {
+                warn!("Client sent an invalid status request");
+            }
+            return;
+        }
+
+        let Some(status) = Self::backend_status(handshake, backend, status_cache).await else {
+            return;
+        };
+
+        if let Err(e) = self.client.write_packet(&status).await {
+            warn!("Failed to send status response to client");
+            debug!("Error: {e}");
+            return;
+        }
+
+        // The client may close the connection instead of measuring the latency
+        if let Ok(ping) = read_packet_within::<Ping>(
+            &mut self.client,
+            Duration::from_millis(config.handshake_timeout_ms),
+            config.max_login_packet_length,
+        )
+        .await
+            && let Err(e) = self.client.write_packet(&ping).await
+        {
+            debug!("Failed to answer ping of client: {e}");
         }
     }
 
-    async fn forward_status(&mut self, handshake: &Handshake) {
-        if let Err(e) = self.backend.write_packet(handshake).await {
-            warn!("Failed to forward status handshake to backend: {e}");
+    // Answers the server list ping of clients before 1.7 with the status of the backend
+    async fn answer_legacy_ping(
+        &mut self,
+        config: &TomlConfig,
+        backend: &BackendConfig,
+        status_cache: &StatusCache,
+    ) {
+        let timeout = Duration::from_millis(config.handshake_timeout_ms);
+        if let Err(e) = tokio::time::timeout(timeout, read_legacy_ping(&mut self.client))
+            .await
+            .unwrap_or_else(|_| Err(timed_out(timeout)))
+        {
+            debug!("Failed to read legacy ping from client: {e}");
+            return;
+        }
+
+        let Some(status) = Self::unversioned_status(backend, status_cache).await else {
             return;
         };
 
-        // Let them to the status exchange normally
-        if let Err(e) = tokio::io::copy_bidirectional(&mut self.client, &mut self.backend).await {
-            warn!("Failed to forward status data between client and backend");
+        let summary = match status.summary() {
+            Ok(summary) => summary,
+            Err(e) => {
+                warn!("Failed to answer legacy ping: {e}");
+                return;
+            }
+        };
+        if let Err(e) = LegacyPingResponse(summary).write(&mut self.client).await {
+            warn!("Failed to send legacy ping response to client");
             debug!("Error: {e}");
+        }
+    }
+
+    // The status of the backend for requests that don't come with a protocol version
+    pub async fn unversioned_status(
+        backend: &BackendConfig,
+        status_cache: &StatusCache,
+    ) -> Option<StatusResponse> {
+        let handshake = Handshake {
+            protocol_version: VarInt::new(UNVERSIONED_STATUS_PROTOCOL).unwrap(),
+            server_address: MCString::new(backend.backend_address.ip().to_string()).unwrap(),
+            server_port: backend.backend_address.port(),
+            next_state: NextState::Status,
         };
+        Self::backend_status(&handshake, backend, status_cache).await
+    }
+
+    // The status shown for the backend to the protocol of the handshake, with the rewriting rules applied
+    // They apply to the offline status as well, so the server list looks the same while the backend is down
+    async fn backend_status(
+        handshake: &Handshake,
+        backend: &BackendConfig,
+        status_cache: &StatusCache,
+    ) -> Option<StatusResponse> {
+        let status = Self::unrewritten_status(handshake, backend, status_cache).await?;
+        if !backend.rewrites_status() {
+            return Some(status);
+        }
+
+        match rewrite_status(&status, backend) {
+            Ok(rewritten) => Some(rewritten),
+            Err(e) => {
+                // The original status is still better than none
+                warn!("Failed to rewrite status response: {e}");
+                Some(status)
+            }
+        }
+    }
+
+    // The status of the backend for the protocol of the handshake, from the cache if it is recent enough
+    async fn unrewritten_status(
+        handshake: &Handshake,
+        backend: &BackendConfig,
+        status_cache: &StatusCache,
+    ) -> Option<StatusResponse> {
+        let protocol = *handshake.protocol_version;
+
+        if let Some(status) = status_cache.get(protocol) {
+            trace!("Answering status request with the cached status");
+            return Some(status);
+        }
+
+        // Only one request at a time asks the backend, the others use what it got
+        let _refreshing = match status_cache.try_refresh() {
+            Some(refreshing) => refreshing,
+            None => {
+                if let Some(status) = status_cache.outdated(protocol) {
+                    trace!(
+                        "Answering status request with the outdated status, as the backend is already being asked"
+                    );
+                    return Some(status);
+                }
+                let refreshing = status_cache.refresh().await;
+                if let Some(status) = status_cache.get(protocol) {
+                    trace!("Answering status request with the status that was just fetched");
+                    return Some(status);
+                }
+                refreshing
+            }
+        };
+
+        if status_cache.recently_failed() {
+            trace!(
+                "Answering status request without asking the backend, as it just failed to answer"
+            );
+            return Self::offline_status(protocol, backend, status_cache);
+        }
+
+        match Self::fetch_status(handshake, backend).await {
+            Some(status) => {
+                status_cache.insert(protocol, status.clone());
+                Some(status)
+            }
+            None => {
+                status_cache.mark_failed();
+                Self::offline_status(protocol, backend, status_cache)
+            }
+        }
+    }
+
+    // The status shown while the backend can't be reached
+    fn offline_status(
+        protocol: i32,
+        backend: &BackendConfig,
+        status_cache: &StatusCache,
+    ) -> Option<StatusResponse> {
+        if backend.offline_use_last_status
+            && let Some(status) = status_cache.last_known(protocol)
+        {
+            trace!("Answering status request with the last known status of the backend");
+            return Some(status);
+        }
+
+        match StatusResponse::synthesized(
+            protocol,
+            &backend.offline_version_name,
+            &backend.offline_motd,
+            backend.offline_online_players,
+            backend.offline_max_players,
+        ) {
+            Ok(status) => Some(status),
+            Err(e) => {
+                error!("Failed to create the offline status: {e}");
+                None
+            }
+        }
+    }
+
+    // Asks the backend for its status like the client would
+    async fn fetch_status(
+        handshake: &Handshake,
+        backend: &BackendConfig,
+    ) -> Option<StatusResponse> {
+        trace!("Requesting status from backend");
+        let mut backend_connection = Self::connect_backend(backend).await?;
+
+        if let Err(e) = async {
+            backend_connection.write_packet(handshake).await?;
+            backend_connection.write_packet(&StatusRequest).await
+        }
+        .await
+        {
+            warn!("Failed to request status from backend: {e}");
+            return None;
+        }
+
+        match read_packet_within::<StatusResponse>(
+            &mut backend_connection,
+            Duration::from_millis(backend.status_read_timeout_ms),
+            MAX_PACKET_LENGTH as usize,
+        )
+        .await
+        {
+            Ok(status) => Some(status),
+            Err(ReadPacketError::Io(e)) => {
+                warn!("Failed to read status response from backend: {e}");
+                None
+            }
+            Err(_) => {
+                warn!("Backend sent an invalid status response");
+                None
+            }
+        }
+    }
+
+    // Opens the connection to the backend once the client is known to need it
+    async fn connect_backend(backend: &BackendConfig) -> Option<TcpStream> {
+        let timeout = Duration::from_millis(backend.backend_connect_timeout_ms);
+
+        let mut attempt = 0;
+        let backend_connection = loop {
+            trace!(
+                "Connecting to backend server at {}",
+                backend.backend_address
+            );
+            match tokio::time::timeout(timeout, TcpStream::connect(backend.backend_address)).await {
+                Ok(Ok(connection)) => break connection,
+                Ok(Err(e)) => debug!("Failed to connect to backend server: {e}"),
+                Err(_) => debug!(
+                    "Connecting to backend server timed out after {}ms",
+                    timeout.as_millis()
+                ),
+            }
+
+            if attempt >= backend.backend_connect_retries {
+                error!(
+                    "Failed to connect to backend server at {} after {} attempts",
+                    backend.backend_address,
+                    attempt + 1
+                );
+                return None;
+            }
+
+            let backoff = CONNECT_RETRY_BACKOFF * 2u32.pow(attempt.min(MAX_BACKOFF_DOUBLINGS));
+            debug!(
+                "Retrying to connect to backend server in {}ms",
+                backoff.as_millis()
+            );
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        };
+
+        // Packets should be forwarded immediately
+        if backend_connection.set_nodelay(true).is_err() {
+            error!("Failed to disable TCP Delay for backend connection");
+            return None;
+        };
+
+        Some(backend_connection)
     }
 
     async fn buffer_until_response(
         &mut self,
-    ) -> tokio::io::Result<(Vec<GenericPacket>, VelocityLoginPluginResponse)> {
+        config: &TomlConfig,
+    ) -> tokio::io::Result<(Vec<GenericPacket>, VelocityLoginPluginAnswer)> {
         let mut serverbound_buffer = Vec::new();
+        let mut buffered_bytes = 0;
         loop {
             trace!("Reading next packet from client while waiting for login plugin response");
             let packet = self
                 .client
-                .read_packet::<VelocityLoginPluginResponse>()
+                .read_packet_limited::<VelocityLoginPluginAnswer>(config.max_login_packet_length)
                 .await;
 
             match packet {
@@ -238,7 +1038,20 @@ impl Connection {
                         trace!(
                             "Buffering packet with id {got:x} while waiting for login plugin response"
                         );
+                        buffered_bytes += packet.data.len();
                         serverbound_buffer.push(packet);
+
+                        if serverbound_buffer.len() > config.max_buffered_packets
+                            || buffered_bytes > config.max_buffered_bytes
+                        {
+                            return Err(tokio::io::Error::new(
+                                tokio::io::ErrorKind::InvalidData,
+                                format!(
+                                    "Client sent {} packets with {buffered_bytes} bytes while waiting for login plugin response, which is more than allowed",
+                                    serverbound_buffer.len()
+                                ),
+                            ));
+                        }
                     }
                     ReadPacketError::PacketSizeMismatch { expected, got } => {
                         if expected < got {
@@ -261,68 +1074,148 @@ impl Connection {
     }
 }
 
+// Whether the client started the connection with a legacy ping, without consuming anything
+async fn starts_with_legacy_ping(stream: &TcpStream, timeout: Duration) -> tokio::io::Result<bool> {
+    let mut start = [0u8; 3];
+    let read = tokio::time::timeout(timeout, stream.peek(&mut start))
+        .await
+        .unwrap_or_else(|_| Err(timed_out(timeout)))?;
+    Ok(is_legacy_ping(&start[..read]))
+}
+
+// Reads a packet, failing with a timeout error if it does not arrive in time
+async fn read_packet_within<P: ReadPacket + Packet<Managed>>(
+    stream: &mut TcpStream,
+    timeout: Duration,
+    max_length: usize,
+) -> Result<P, ReadPacketError> {
+    tokio::time::timeout(timeout, stream.read_packet_limited::<P>(max_length))
+        .await
+        .unwrap_or_else(|_| Err(ReadPacketError::Io(timed_out(timeout))))
+}
+
+// Compares secrets without returning early, so the time taken does not tell how much of them matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+fn timed_out(timeout: Duration) -> tokio::io::Error {
+    tokio::io::Error::new(
+        tokio::io::ErrorKind::TimedOut,
+        format!("Timed out after {}ms", timeout.as_millis()),
+    )
+}
+
+enum ModernForwardingError {
+    // The player could not be verified, the reason was already logged
+    Unverified,
+    // The proxy in front of this one answered the forwarding request without player data
+    NotUnderstood { buffer: Vec<GenericPacket> },
+}
+
+// The player data that was verified to come from the proxy in front of this one
+struct VerifiedPlayer {
+    info: PlayerInfo,
+    // Packets the client sent while the forwarding data was being verified
+    buffer: Vec<GenericPacket>,
+    // The Forge marker, if the proxy in front of this one forwarded it in the properties
+    fml_marker: Option<String>,
+}
+
 pub struct ParitalConnection {
     client: TcpStream,
 }
 
 impl ParitalConnection {
-    pub fn with_backend(
-        self,
-        backend: TcpStream,
-        connection_id: i32,
-    ) -> Result<Connection, &'static str> {
-        // Packets should be forwarded immediately
-        if backend.set_nodelay(true).is_err() {
-            return Err("Failed to disable TCP Delay for backend connection");
-        };
-
-        Ok(Connection {
+    // Accepts a client from a trusted address, the backend is only connected to once the client needs it
+    pub fn accept(self) -> Connection {
+        Connection {
             client: self.client,
-            backend,
-            connection_id,
-        })
+        }
+    }
+
+    pub async fn reject_untrusted(self, config: &TomlConfig) {
+        let rejected = self
+            .reject(
+                Duration::from_millis(config.handshake_timeout_ms),
+                config.max_login_packet_length,
+                "You are not allowed to connect to this server directly!",
+            )
+            .await;
+        match rejected {
+            Some(NextState::Status) => trace!("Rejected untrusted connection for a status request"),
+            Some(NextState::Login) => warn!("Rejected untrusted connection for a login request"),
+            Some(NextState::Transfer) => {
+                warn!("Rejected untrusted connection for a transfer request")
+            }
+            None => (),
+        }
+    }
+
+    // Tells a client that the connection limit is reached, this is logged by the listener
+    pub async fn reject_over_limit(self, config: &TomlConfig) {
+        self.reject(
+            OVER_LIMIT_HANDSHAKE_TIMEOUT,
+            config.max_login_packet_length,
+            "The server is too busy right now, please try again later",
+        )
+        .await;
     }
 
-    pub async fn reject_untrusted(mut self) {
-        let handshake = match self.client.read_packet::<Handshake>().await {
+    // Reads the handshake and disconnects logins with the reason, returns what the client wanted if it sent a handshake
+    async fn reject(
+        mut self,
+        handshake_timeout: Duration,
+        max_handshake_length: usize,
+        reason: &str,
+    ) -> Option<NextState> {
+        match starts_with_legacy_ping(&self.client, handshake_timeout).await {
+            Ok(true) => {
+                trace!("Rejecting connection for a legacy ping");
+                return None;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                debug!("Failed to read handshake from client: {e}");
+                return None;
+            }
+        }
+
+        let handshake = match read_packet_within::<Handshake>(
+            &mut self.client,
+            handshake_timeout,
+            max_handshake_length,
+        )
+        .await
+        {
             Ok(handshake) => handshake,
             Err(e) => {
                 match e {
                     ReadPacketError::Io(error) => {
-                        error!("Failed to read handshake from client: {error}",);
-                        return;
+                        debug!("Failed to read handshake from client: {error}");
                     }
                     ReadPacketError::InvalidPacketId { got, .. } => {
-                        warn!("Client sent invalid packet id {got:x} for handshake",);
+                        debug!("Client sent invalid packet id {got:x} for handshake");
                     }
                     ReadPacketError::PacketSizeMismatch { .. } => {
-                        warn!("Clientsent handshake with invalid length",);
+                        debug!("Client sent handshake with invalid length");
                     }
                 }
-                return;
+                return None;
             }
         };
-        match handshake.next_state {
-            NextState::Status => trace!("Rejecting untrusted connection for a status request",),
-            NextState::Login => {
-                warn!("Rejecting untrusted connection for a login request",);
-
-                if let Err(e) = self
-                    .client
-                    .write_packet(&Disconnect::reason(
-                        "You are not allowed to connect to this server directly!",
-                    ))
-                    .await
-                {
-                    warn!("Failed to send disconnect packet to untrusted client",);
-                    debug!("Error: {e}");
-                    return;
-                };
-            }
-            NextState::Transfer => {
-                warn!("Rejecting untrusted connection for a transfer request",);
-            }
+        if let NextState::Login | NextState::Transfer = handshake.next_state
+            && let Err(e) = self.client.write_packet(&Disconnect::reason(reason)).await
+        {
+            warn!("Failed to send disconnect packet to rejected client");
+            debug!("Error: {e}");
+            return Some(handshake.next_state);
         }
         self.client.shutdown().await.ok();
+        Some(handshake.next_state)
     }
 }
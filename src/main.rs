@@ -1,10 +1,20 @@
-use std::path::Path;
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use time::macros::format_description;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::Semaphore,
+};
 
 use tokio_util::sync::CancellationToken;
-use tracing::{Instrument, Level, error, info, level_filters::LevelFilter, span, trace, warn};
+use tracing::{
+    Instrument, Level, debug, error, info, level_filters::LevelFilter, span, trace, warn,
+};
 use tracing_subscriber::{
     Registry,
     fmt::{self, time::LocalTime},
@@ -14,15 +24,30 @@ use tracing_subscriber::{
 };
 
 use crate::{
-    config::{ConfigError, TomlConfig},
+    config::{BackendConfig, ConfigError, OutboundForwarding, TomlConfig},
     connection::Connection,
+    online_players::OnlinePlayers,
+    packets::protocol::ProtocolVersion,
+    query::listen_query,
+    status_cache::StatusCache,
 };
 
+mod clientbound;
 mod config;
 mod connection;
+mod online_players;
 mod packets;
+mod query;
+mod status_cache;
+mod status_rewrite;
 mod types;
 
+// Connections over the connection limit that are told so at the same time, any more are closed right away
+const MAX_OVER_LIMIT_REJECTIONS: usize = 64;
+
+// How often reaching the connection limit is logged at most
+const OVER_LIMIT_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
 static CONFIG_PATH: &str = "Config.toml";
 
 #[tokio::main]
@@ -37,7 +62,15 @@ async fn main() {
                 | ConfigError::Read(_)
                 | ConfigError::Write(_)
                 | ConfigError::Parse(_)
-                | ConfigError::NoSecret => {
+                | ConfigError::NoSecret
+                | ConfigError::SecretFile(..)
+                | ConfigError::EmptyAdditionalSecret(_)
+                | ConfigError::NoBungeeGuardToken(_)
+                | ConfigError::NoBackendSecret(_)
+                | ConfigError::UnverifiedForwarding
+                | ConfigError::InvalidForwardingVersion(_)
+                | ConfigError::Favicon(..)
+                | ConfigError::InvalidFavicon(..) => {
                     error!("{e}");
                 }
                 ConfigError::CreatedNew(_) => info!("{e}"),
@@ -52,32 +85,121 @@ async fn main() {
         return;
     };
 
+    config.log_secret_fingerprints();
+
+    // Load the protocol table now, so a broken table is noticed before the first player connects
+    let known_versions = ProtocolVersion::known();
+    debug!(
+        "Loaded {} known protocol versions, the newest is {}",
+        known_versions.len(),
+        known_versions
+            .last()
+            .map_or("none", |version| &version.name)
+    );
+
+    for backend in config.backends() {
+        if backend.outbound_forwarding == OutboundForwarding::Legacy
+            && backend.bungeeguard_tokens.is_empty()
+        {
+            warn!(
+                "No BungeeGuard token configured for the backend at {}, anyone who can reach it directly can impersonate any player",
+                backend.backend_address
+            );
+        }
+    }
+
+    let config = Arc::new(config);
+
     // Setup shutdown signal
     let cancel = CancellationToken::new();
     tokio::spawn(shutdown_signal(cancel.clone()));
 
-    // Start listening for clients
-    let client_listener = match TcpListener::bind(config.bind_address).await {
-        Ok(listener) => listener,
-        Err(e) => {
-            error!("Failed to bind to {}: {e}", config.bind_address);
-            return;
+    // The connection limit is shared, so it applies to all backends together
+    let connection_limit = config
+        .max_connections
+        .map(|max_connections| Arc::new(Semaphore::new(max_connections)));
+
+    // Start listening for clients of every backend
+    let mut listeners = Vec::new();
+    for backend in config.backends() {
+        let client_listener = match TcpListener::bind(backend.bind_address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind to {}: {e}", backend.bind_address);
+                return;
+            }
+        };
+
+        info!(
+            "Listening for client connections on {}, forwarding to {}",
+            backend.bind_address, backend.backend_address
+        );
+
+        let backend = Arc::new(backend.clone());
+        let status_cache = Arc::new(StatusCache::new(Duration::from_millis(
+            backend.status_cache_ttl_ms,
+        )));
+        let online_players = Arc::new(OnlinePlayers::default());
+
+        if let Some(query_port) = backend.query_port {
+            let query_address = SocketAddr::new(backend.bind_address.ip(), query_port);
+            let query_socket = match UdpSocket::bind(query_address).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    error!("Failed to bind to {query_address} for queries: {e}");
+                    return;
+                }
+            };
+
+            info!("Answering queries on {query_address}");
+
+            listeners.push(tokio::spawn(listen_query(
+                Arc::new(query_socket),
+                backend.clone(),
+                status_cache.clone(),
+                online_players.clone(),
+                cancel.clone(),
+            )));
         }
-    };
 
-    info!(
-        "Listening for client connections on {}",
-        config.bind_address
-    );
+        listeners.push(tokio::spawn(listen(
+            client_listener,
+            backend,
+            status_cache,
+            online_players,
+            config.clone(),
+            connection_limit.clone(),
+            cancel.clone(),
+        )));
+    }
 
-    let mut connection_id = 0i32;
+    for listener in listeners {
+        listener.await.ok();
+    }
+
+    info!("Successfully shut down");
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn listen(
+    client_listener: TcpListener,
+    backend: Arc<BackendConfig>,
+    status_cache: Arc<StatusCache>,
+    online_players: Arc<OnlinePlayers>,
+    config: Arc<TomlConfig>,
+    connection_limit: Option<Arc<Semaphore>>,
+    cancel: CancellationToken,
+) {
+    let over_limit_rejections = Arc::new(Semaphore::new(MAX_OVER_LIMIT_REJECTIONS));
+    let mut over_limit_closed = 0u64;
+    let mut over_limit_logged_at: Option<Instant> = None;
 
     // Wait for connections
     loop {
         // Wait for cancellation or accept new connection
         let (client_connection, client_adress) = tokio::select! {
             _ = cancel.cancelled() => {
-                trace!("Shutting down connection listener");
+                trace!("Shutting down connection listener on {}", backend.bind_address);
                 break;
             }
             accept_result = client_listener.accept() => {
@@ -93,55 +215,99 @@ async fn main() {
 
         let connection_span = span!(Level::TRACE, "connection", %client_adress);
 
-        let connection = match Connection::initiate(client_connection) {
-            Ok(c) => c,
-            Err(e) => {
-                error!(parent: &connection_span, "{e}");
-                continue;
-            }
-        };
-
-        trace!(parent: &connection_span, "New client connection from {client_adress}");
-
-        // Reject untrusted connections
-        if !config.trusted_ips.is_empty() && !config.trusted_ips.contains(&client_adress.ip()) {
-            warn!(parent: &connection_span, "Rejecting connection from untrusted address {client_adress}");
-            connection
-                .reject_untrusted()
-                .instrument(connection_span)
-                .await;
-            continue;
-        }
-
-        // Create a backend connection for this connection
-        let Ok(backend_connection) = TcpStream::connect(config.backend_address).await else {
-            error!(parent: &connection_span, "Failed to connect to backend server");
-            continue;
-        };
-
-        tokio::task::spawn({
-            let mut connection = match connection.with_backend(backend_connection, connection_id) {
-                Ok(c) => c,
-                Err(e) => {
-                    error!(parent: &connection_span, "{e}");
+        // The permit is held until the connection is closed
+        let permit = match &connection_limit {
+            Some(limit) => match limit.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    over_limit_closed += 1;
+                    if over_limit_logged_at
+                        .is_none_or(|logged_at| logged_at.elapsed() >= OVER_LIMIT_LOG_INTERVAL)
+                    {
+                        warn!(
+                            "The connection limit is reached, {over_limit_closed} connections were closed since this was last logged"
+                        );
+                        over_limit_closed = 0;
+                        over_limit_logged_at = Some(Instant::now());
+                    }
+                    debug!(parent: &connection_span, "Closing connection from {client_adress}, as the connection limit is reached");
+
+                    // Clients are told why, as long as that does not become a flood of its own
+                    if let Ok(rejection) = over_limit_rejections.clone().try_acquire_owned() {
+                        let config = config.clone();
+                        tokio::task::spawn(
+                            async move {
+                                let _rejection = rejection;
+                                if let Ok(connection) = Connection::initiate(client_connection) {
+                                    connection.reject_over_limit(&config).await;
+                                }
+                            }
+                            .instrument(connection_span),
+                        );
+                    }
                     continue;
                 }
-            };
+            },
+            None => None,
+        };
 
-            let secret = config.forwarding_secret.clone();
+        // Everything else happens in its own task, so no client can hold up accepting the next one
+        tokio::task::spawn({
+            let config = config.clone();
+            let backend = backend.clone();
+            let status_cache = status_cache.clone();
+            let online_players = online_players.clone();
             let cancel = cancel.clone();
 
             async move {
-                connection
-                    .handle(secret, cancel)
-                    .instrument(connection_span)
-                    .await
+                let _permit = permit;
+                handle_client(
+                    client_connection,
+                    client_adress,
+                    config,
+                    backend,
+                    status_cache,
+                    online_players,
+                    cancel,
+                )
+                .await;
             }
+            .instrument(connection_span)
         });
-        connection_id = connection_id.wrapping_add(1);
     }
+}
 
-    info!("Successfully shut down");
+#[allow(clippy::too_many_arguments)]
+async fn handle_client(
+    client_connection: TcpStream,
+    client_adress: SocketAddr,
+    config: Arc<TomlConfig>,
+    backend: Arc<BackendConfig>,
+    status_cache: Arc<StatusCache>,
+    online_players: Arc<OnlinePlayers>,
+    cancel: CancellationToken,
+) {
+    let connection = match Connection::initiate(client_connection) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{e}");
+            return;
+        }
+    };
+
+    trace!("New client connection from {client_adress}");
+
+    // Reject untrusted connections
+    if !config.trusted_ips.is_empty() && !config.trusted_ips.contains(&client_adress.ip()) {
+        warn!("Rejecting connection from untrusted address {client_adress}");
+        connection.reject_untrusted(&config).await;
+        return;
+    }
+
+    connection
+        .accept()
+        .handle(config, backend, status_cache, online_players, cancel)
+        .await;
 }
 
 async fn shutdown_signal(cancel: CancellationToken) {
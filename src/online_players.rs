@@ -0,0 +1,48 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+// The players currently forwarded to a backend through this proxy
+#[derive(Default)]
+pub struct OnlinePlayers {
+    state: Mutex<OnlinePlayersState>,
+}
+
+#[derive(Default)]
+struct OnlinePlayersState {
+    next_id: u64,
+    // By the order they joined in
+    names: BTreeMap<u64, Arc<str>>,
+}
+
+// Keeps a player listed as online until it is dropped
+pub struct OnlinePlayer {
+    players: Arc<OnlinePlayers>,
+    id: u64,
+}
+
+impl OnlinePlayers {
+    pub fn join(self: &Arc<Self>, name: &str) -> OnlinePlayer {
+        let mut state = self.state.lock().unwrap();
+
+        let id = state.next_id;
+        state.next_id += 1;
+        state.names.insert(id, name.into());
+
+        OnlinePlayer {
+            players: self.clone(),
+            id,
+        }
+    }
+
+    pub fn names(&self) -> Vec<Arc<str>> {
+        self.state.lock().unwrap().names.values().cloned().collect()
+    }
+}
+
+impl Drop for OnlinePlayer {
+    fn drop(&mut self) {
+        self.players.state.lock().unwrap().names.remove(&self.id);
+    }
+}
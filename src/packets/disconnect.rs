@@ -3,6 +3,7 @@ use crate::{
         Packet,
         id::{Managed, VersionDependent},
         packet_write::WritePacket,
+        protocol::{ProtocolVersion, TextComponentFormat},
     },
     types::{MCData, MCString},
 };
@@ -14,7 +15,11 @@ pub struct Disconnect {
 impl Disconnect {
     pub fn reason(reason: &str) -> Self {
         Self {
-            reason: MCString::new(format!(r#"{{"text": "{reason}", "color": "red"}}"#)).unwrap(),
+            reason: MCString::new(format!(
+                r#"{{"text": {}, "color": "red"}}"#,
+                serde_json::Value::from(reason)
+            ))
+            .unwrap(),
         }
     }
 }
@@ -37,26 +42,74 @@ impl WritePacket for Disconnect {
 }
 
 pub struct PlayDisconnect {
-    inner: Disconnect,
+    reason: PlayDisconnectReason,
+}
+
+enum PlayDisconnectReason {
+    Json(Disconnect),
+    // Since 1.20.3 text components are sent as NBT
+    Nbt(Vec<u8>),
 }
 
 impl PlayDisconnect {
-    pub fn reason(reason: &str) -> Self {
-        Self {
-            inner: Disconnect::reason(reason),
-        }
+    pub fn reason(reason: &str, protocol: i32) -> Self {
+        let text_components = ProtocolVersion::get(protocol)
+            .map_or(TextComponentFormat::Json, |version| version.text_components);
+
+        let reason = if text_components == TextComponentFormat::Nbt {
+            // A nameless compound tag with the string tags "text" and "color"
+            let mut nbt = vec![0x0A];
+            for (name, value) in [("text", reason), ("color", "red")] {
+                nbt.push(0x08);
+                for string in [name, value] {
+                    nbt.extend_from_slice(&(string.len() as u16).to_be_bytes());
+                    nbt.extend_from_slice(string.as_bytes());
+                }
+            }
+            nbt.push(0x00); // End of the compound
+            PlayDisconnectReason::Nbt(nbt)
+        } else {
+            PlayDisconnectReason::Json(Disconnect::reason(reason))
+        };
+
+        Self { reason }
     }
 
-    const fn protocol_id(protocol: i32) -> Option<u8> {
-        match protocol {
-            0..67 => Some(0x40),
-            67..80 => Some(0x19),
-            80..318 => Some(0x1A),
-            318..332 => Some(0x1B),
-            332..=340 => Some(0x1A),
-            // Version 340 is 1.12.2, the earliest last non-modern forwarding version
-            _ => None,
-        }
+    fn protocol_id(protocol: i32) -> Option<u8> {
+        ProtocolVersion::get(protocol).map(|version| version.play.disconnect)
+    }
+}
+
+// The disconnect packet of the configuration state of 1.20.2 and newer, it looks like the one of the play state
+pub struct ConfigurationDisconnect(PlayDisconnect);
+
+impl ConfigurationDisconnect {
+    pub fn reason(reason: &str, protocol: i32) -> Self {
+        Self(PlayDisconnect::reason(reason, protocol))
+    }
+
+    fn protocol_id(protocol: i32) -> Option<u8> {
+        ProtocolVersion::get(protocol)?
+            .configuration
+            .as_ref()
+            .map(|ids| ids.disconnect)
+    }
+}
+
+impl Packet<VersionDependent> for ConfigurationDisconnect {
+    const PACKET_ID: VersionDependent = VersionDependent(Self::protocol_id);
+
+    fn byte_size(&self) -> usize {
+        self.0.byte_size()
+    }
+}
+
+impl WritePacket for ConfigurationDisconnect {
+    async fn write<W: tokio::io::AsyncWriteExt + Unpin>(
+        &self,
+        writer: &mut W,
+    ) -> tokio::io::Result<()> {
+        self.0.write(writer).await
     }
 }
 
@@ -64,7 +117,10 @@ impl Packet<VersionDependent> for PlayDisconnect {
     const PACKET_ID: VersionDependent = VersionDependent(Self::protocol_id);
 
     fn byte_size(&self) -> usize {
-        self.inner.byte_size()
+        match &self.reason {
+            PlayDisconnectReason::Json(disconnect) => disconnect.byte_size(),
+            PlayDisconnectReason::Nbt(nbt) => nbt.len(),
+        }
     }
 }
 
@@ -73,6 +129,9 @@ impl WritePacket for PlayDisconnect {
         &self,
         writer: &mut W,
     ) -> tokio::io::Result<()> {
-        self.inner.write(writer).await
+        match &self.reason {
+            PlayDisconnectReason::Json(disconnect) => disconnect.write(writer).await,
+            PlayDisconnectReason::Nbt(nbt) => writer.write_all(nbt).await,
+        }
     }
 }
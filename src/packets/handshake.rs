@@ -1,5 +1,6 @@
+use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::{
     packets::{
@@ -9,6 +10,16 @@ use crate::{
     types::{MCData, MCString, NextState, Uuid, VarInt},
 };
 
+// The property name BungeeGuard looks for in the forwarded properties
+pub const BUNGEEGUARD_TOKEN_PROPERTY: &str = "bungeeguard-token";
+
+// Forge clients append a marker like "\0FML\0", "\0FML2\0" or "\0FML3\0" to the server address
+const FML_MARKER_START: &str = "\0FML";
+
+// BungeeCord moves the marker into these properties when forwarding, as the "\0" would break the forwarding data
+const FORGE_CLIENT_PROPERTY: &str = "forgeClient";
+const FORGE_EXTRA_DATA_PROPERTY: &str = "extraData";
+
 pub struct Handshake {
     pub protocol_version: VarInt,
     pub server_address: MCString<32767>,
@@ -16,47 +27,211 @@ pub struct Handshake {
     pub next_state: NextState,
 }
 
-impl Handshake {
-    pub async fn insert_forwarding_data(
-        &mut self,
-        client_address: MCString<32767>,
-        player_uuid: Uuid,
-        properties: &[Property],
-    ) {
+// The player data a BungeeCord style proxy puts into the server address of the handshake
+pub struct BungeeForwardingData {
+    pub server_address: MCString<32767>,
+    pub client_address: MCString<32767>,
+    pub player_uuid: Uuid,
+    pub properties: Vec<Property>,
+    pub fml_marker: Option<String>,
+}
+
+// Spigot and Paper accept server addresses of up to this many characters when BungeeCord forwarding is enabled
+const MAX_FORWARDED_ADDRESS_LENGTH: usize = i16::MAX as usize;
+
+// The representation of a property in the forwarded JSON
+#[derive(Serialize, Deserialize)]
+struct JsonProperty {
+    name: String,
+    value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+impl BungeeForwardingData {
+    // Parses the forwarding data a BungeeCord style proxy put into a server address
+    pub fn decode(address: &str) -> Result<Self, &'static str> {
+        // A marker can only be at the end, after the forwarding data
+        let (address, appended_fml_marker) = match address.find(FML_MARKER_START) {
+            Some(start) => (&address[..start], Some(address[start..].to_owned())),
+            None => (address, None),
+        };
+
+        let mut parts = address.split('\0');
+
+        let (Some(server_address), Some(client_address), Some(player_uuid)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err("The handshake does not contain any forwarding data");
+        };
+
+        let player_uuid = player_uuid.replace('-', "");
+        if player_uuid.len() != 32 {
+            return Err("The forwarded player uuid is invalid");
+        }
+        let player_uuid = u128::from_str_radix(&player_uuid, 16)
+            .map_err(|_| "The forwarded player uuid is invalid")?;
+
+        let mut properties = match parts.next() {
+            Some(properties) => serde_json::from_str::<Vec<JsonProperty>>(properties)
+                .map_err(|_| "The forwarded properties are not valid JSON")?
+                .into_iter()
+                .map(|property| {
+                    Ok(Property {
+                        name: MCString::new(property.name)?,
+                        value: MCString::new(property.value)?,
+                        signature: property.signature.map(MCString::new).transpose()?,
+                    })
+                })
+                .collect::<Result<Vec<_>, &'static str>>()?,
+            None => Vec::new(),
+        };
+
+        if parts.next().is_some() {
+            return Err("The handshake contains more forwarding data than expected");
+        }
+
+        // Recover the marker of Forge clients from the properties BungeeCord put it in
+        let property_fml_marker = properties
+            .iter()
+            .find(|property| property.name.as_str() == FORGE_EXTRA_DATA_PROPERTY)
+            .map(|property| property.value.as_str().replace('\u{1}', "\0"))
+            .filter(|marker| marker.starts_with(FML_MARKER_START));
+        if property_fml_marker.is_some() {
+            properties.retain(|property| {
+                property.name.as_str() != FORGE_CLIENT_PROPERTY
+                    && property.name.as_str() != FORGE_EXTRA_DATA_PROPERTY
+            });
+        }
+
+        Ok(BungeeForwardingData {
+            server_address: MCString::new(server_address.to_owned())?,
+            client_address: MCString::new(client_address.to_owned())?,
+            player_uuid: Uuid(player_uuid),
+            properties,
+            fml_marker: appended_fml_marker.or(property_fml_marker),
+        })
+    }
+
+    // Builds the server address containing the forwarding data like BungeeCord does, with the marker appended if there is one
+    pub fn encode(&self, bungeeguard_token: Option<&str>) -> Result<String, &'static str> {
+        // Anything after a "\0" in the host would be read as forwarding data by the backend
+        let server_address = self.server_address.as_str();
+        let host = match server_address.split_once('\0') {
+            Some((host, _)) => {
+                warn!(
+                    "Stripping unexpected data from the server address {}",
+                    server_address.replace('\0', "\\0")
+                );
+                host
+            }
+            None => server_address,
+        };
+
+        if self.client_address.as_str().contains('\0') {
+            return Err("The client address contains a null character");
+        }
+
         let mut forwarding_data = format!(
-            "{}\0{}\0{:x}",
-            self.server_address.as_str(),
-            client_address.as_str(),
-            *player_uuid
+            "{host}\0{}\0{:032x}",
+            self.client_address.as_str(),
+            *self.player_uuid
         );
 
-        if !properties.is_empty() {
-            forwarding_data.push_str("\0[");
+        // A token coming from anywhere else than this proxy should never reach the backend
+        let mut properties = self
+            .properties
+            .iter()
+            .filter(|property| property.name.as_str() != BUNGEEGUARD_TOKEN_PROPERTY)
+            .map(|property| JsonProperty {
+                name: property.name.to_string(),
+                value: property.value.to_string(),
+                signature: property.signature.as_ref().map(ToString::to_string),
+            })
+            .collect::<Vec<_>>();
 
-            for property in properties {
-                forwarding_data.push('{');
+        if let Some(token) = bungeeguard_token {
+            properties.push(JsonProperty {
+                name: BUNGEEGUARD_TOKEN_PROPERTY.to_owned(),
+                value: token.to_owned(),
+                signature: None,
+            });
+        }
 
-                forwarding_data.push_str(r#""name":""#);
-                forwarding_data.push_str(property.name.as_str());
-                forwarding_data.push_str("\",");
+        if !properties.is_empty() {
+            forwarding_data.push('\0');
+            forwarding_data.push_str(
+                &serde_json::to_string(&properties)
+                    .map_err(|_| "The forwarded properties could not be encoded")?,
+            );
+        }
 
-                forwarding_data.push_str(r#""value":""#);
-                forwarding_data.push_str(property.value.as_str());
-                forwarding_data.push('"');
+        if let Some(marker) = &self.fml_marker {
+            forwarding_data.push_str(marker);
+        }
 
-                if let Some(signature) = &property.signature {
-                    forwarding_data.push_str(r#","signature":""#);
-                    forwarding_data.push_str(signature.as_str());
-                    forwarding_data.push('\"');
-                }
-                forwarding_data.push_str("},");
-            }
-            forwarding_data.pop(); // Remove the last ',' at the end of the list
-            forwarding_data.push(']');
+        if forwarding_data.encode_utf16().count() > MAX_FORWARDED_ADDRESS_LENGTH {
+            return Err("The forwarding data is longer than the backend accepts");
         }
 
-        debug!("{}", forwarding_data.replace('\0', "\\0"));
-        self.server_address = MCString::new(forwarding_data).unwrap();
+        Ok(forwarding_data)
+    }
+}
+
+impl Handshake {
+    // Splits the forwarding data a BungeeCord style proxy inserted into the server address
+    pub fn extract_forwarding_data(&self) -> Result<BungeeForwardingData, &'static str> {
+        BungeeForwardingData::decode(self.server_address.as_str())
+    }
+
+    // Removes the marker Forge clients append to the server address and returns it
+    pub fn take_fml_marker(&mut self) -> Option<String> {
+        let address = self.server_address.as_str();
+        let start = address.find(FML_MARKER_START)?;
+
+        let marker = address[start..].to_owned();
+        self.server_address = MCString::new(address[..start].to_owned()).unwrap();
+        Some(marker)
+    }
+
+    // Puts the marker of a Forge client back at the end of the server address
+    pub fn append_fml_marker(&mut self, marker: &str) -> Result<(), &'static str> {
+        self.server_address = MCString::new(format!("{}{marker}", self.server_address))?;
+        Ok(())
+    }
+
+    // The properties BungeeCord uses to forward the marker of a Forge client
+    pub fn fml_marker_properties(marker: &str) -> [Property; 2] {
+        [
+            Property {
+                name: MCString::new(FORGE_CLIENT_PROPERTY.to_owned()).unwrap(),
+                value: MCString::new("true".to_owned()).unwrap(),
+                signature: None,
+            },
+            Property {
+                name: MCString::new(FORGE_EXTRA_DATA_PROPERTY.to_owned()).unwrap(),
+                value: MCString::new(marker.replace('\0', "\u{1}")).unwrap(),
+                signature: Some(MCString::new(String::new()).unwrap()),
+            },
+        ]
+    }
+
+    // Replaces the server address with the forwarding data for the backend
+    pub fn insert_forwarding_data(
+        &mut self,
+        forwarding_data: &BungeeForwardingData,
+        bungeeguard_token: Option<&str>,
+    ) -> Result<(), &'static str> {
+        let forwarding_data = forwarding_data.encode(bungeeguard_token)?;
+
+        let logged_data = forwarding_data.replace('\0', "\\0");
+        match bungeeguard_token {
+            // Don't leak the token into the logs
+            Some(token) => debug!("{}", logged_data.replace(token, "<bungeeguard token>")),
+            None => debug!("{logged_data}"),
+        }
+        self.server_address = MCString::new(forwarding_data)?;
+        Ok(())
     }
 }
 
@@ -93,3 +268,120 @@ impl WritePacket for Handshake {
         self.next_state.write(writer).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> MCString<32767> {
+        MCString::new(value.to_owned()).unwrap()
+    }
+
+    fn property(name: &str, value: &str, signature: Option<&str>) -> Property {
+        Property {
+            name: string(name),
+            value: string(value),
+            signature: signature.map(string),
+        }
+    }
+
+    fn forwarding_data(server_address: &str, properties: Vec<Property>) -> BungeeForwardingData {
+        BungeeForwardingData {
+            server_address: string(server_address),
+            client_address: string("127.0.0.1"),
+            player_uuid: Uuid(0x1234),
+            properties,
+            fml_marker: None,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = forwarding_data(
+            "mc.example.com",
+            vec![property("textures", "abc", Some("signed"))],
+        );
+        let decoded = BungeeForwardingData::decode(&data.encode(None).unwrap()).unwrap();
+
+        assert_eq!(decoded.server_address.as_str(), "mc.example.com");
+        assert_eq!(decoded.client_address.as_str(), "127.0.0.1");
+        assert_eq!(*decoded.player_uuid, 0x1234);
+        assert_eq!(decoded.properties.len(), 1);
+        assert_eq!(decoded.properties[0].name.as_str(), "textures");
+        assert_eq!(decoded.properties[0].value.as_str(), "abc");
+        assert_eq!(
+            decoded.properties[0]
+                .signature
+                .as_ref()
+                .map(MCString::as_str),
+            Some("signed")
+        );
+        assert!(decoded.fml_marker.is_none());
+    }
+
+    #[test]
+    fn uuid_keeps_leading_zeros() {
+        let encoded = forwarding_data("mc.example.com", Vec::new())
+            .encode(None)
+            .unwrap();
+        assert_eq!(
+            encoded,
+            "mc.example.com\x00127.0.0.1\x0000000000000000000000000000001234"
+        );
+    }
+
+    #[test]
+    fn properties_are_escaped() {
+        let encoded = forwarding_data(
+            "mc.example.com",
+            vec![property("name", "a \"quoted\" \\ value", None)],
+        )
+        .encode(None)
+        .unwrap();
+
+        assert!(encoded.ends_with(r#"[{"name":"name","value":"a \"quoted\" \\ value"}]"#));
+        let decoded = BungeeForwardingData::decode(&encoded).unwrap();
+        assert_eq!(
+            decoded.properties[0].value.as_str(),
+            "a \"quoted\" \\ value"
+        );
+    }
+
+    #[test]
+    fn null_character_is_stripped_from_host() {
+        let encoded = forwarding_data("mc.example.com\0injected", Vec::new())
+            .encode(None)
+            .unwrap();
+        assert!(encoded.starts_with("mc.example.com\x00127.0.0.1\x00"));
+        assert!(!encoded.contains("injected"));
+    }
+
+    #[test]
+    fn fml_marker_in_properties() {
+        let data = forwarding_data(
+            "mc.example.com",
+            Handshake::fml_marker_properties("\0FML2\0").into(),
+        );
+        let encoded = data.encode(None).unwrap();
+
+        assert!(encoded.ends_with(
+            r#"[{"name":"forgeClient","value":"true"},{"name":"extraData","value":"\u0001FML2\u0001","signature":""}]"#
+        ));
+        let decoded = BungeeForwardingData::decode(&encoded).unwrap();
+        assert_eq!(decoded.fml_marker.as_deref(), Some("\0FML2\0"));
+        assert!(decoded.properties.is_empty());
+    }
+
+    #[test]
+    fn too_long_is_rejected() {
+        let data = forwarding_data(
+            "mc.example.com",
+            vec![property(
+                "textures",
+                &"a".repeat(MAX_FORWARDED_ADDRESS_LENGTH),
+                None,
+            )],
+        );
+        assert!(data.encode(None).is_err());
+    }
+}
@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::packets::StatusSummary;
+
+// Clients before 1.7 start their server list ping with this byte instead of a packet length
+const LEGACY_PING_ID: u8 = 0xFE;
+// 1.4 and newer clients follow it with this byte, asking for the "§1" response format
+const LEGACY_PING_PAYLOAD: u8 = 0x01;
+// 1.6 clients then send a plugin message with the address they connected to
+const LEGACY_PLUGIN_MESSAGE_ID: u8 = 0xFA;
+
+// The kick packet old servers answer the ping with
+const LEGACY_KICK_ID: u8 = 0xFF;
+
+// The protocol vanilla servers since 1.7 report to legacy pings, so old clients show them as incompatible
+const LEGACY_PING_PROTOCOL: i32 = 127;
+
+// Old clients refuse longer responses
+const MAX_LEGACY_RESPONSE_LENGTH: usize = 256;
+
+// The plugin message of 1.6 clients is much shorter, anything longer is not read
+const MAX_LEGACY_PLUGIN_MESSAGE_LENGTH: usize = 512;
+
+// Older clients send nothing after the first bytes, so the bytes of newer clients are only waited for this long
+const LEGACY_PING_FOLLOW_UP_WAIT: Duration = Duration::from_millis(100);
+
+// Whether the first bytes a client sent are a legacy ping rather than a modern handshake
+pub fn is_legacy_ping(start: &[u8]) -> bool {
+    // A handshake can start with the same two bytes when it is 254 bytes long, but its packet id follows them
+    match start {
+        [LEGACY_PING_ID] | [LEGACY_PING_ID, LEGACY_PING_PAYLOAD] => true,
+        [LEGACY_PING_ID, LEGACY_PING_PAYLOAD, next, ..] => *next == LEGACY_PLUGIN_MESSAGE_ID,
+        _ => false,
+    }
+}
+
+// Reads the whole legacy ping, so no unread bytes make closing the connection discard the response
+pub async fn read_legacy_ping<R: AsyncReadExt + Unpin>(reader: &mut R) -> tokio::io::Result<()> {
+    reader.read_u8().await?;
+
+    for expected in [LEGACY_PING_PAYLOAD, LEGACY_PLUGIN_MESSAGE_ID] {
+        match tokio::time::timeout(LEGACY_PING_FOLLOW_UP_WAIT, reader.read_u8()).await {
+            Ok(Ok(byte)) if byte == expected => {}
+            // The ping of an older client ends here
+            Ok(Ok(_)) | Err(_) => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+
+    // The "MC|PingHost" channel as UTF-16 with its length in characters, then the length prefixed payload
+    let channel_length = reader.read_u16().await? as usize * 2;
+    skip_legacy_plugin_message_part(reader, channel_length).await?;
+    let payload_length = reader.read_u16().await? as usize;
+    skip_legacy_plugin_message_part(reader, payload_length).await
+}
+
+async fn skip_legacy_plugin_message_part<R: AsyncReadExt + Unpin>(
+    reader: &mut R,
+    length: usize,
+) -> tokio::io::Result<()> {
+    if length > MAX_LEGACY_PLUGIN_MESSAGE_LENGTH {
+        return Err(tokio::io::Error::new(
+            tokio::io::ErrorKind::InvalidData,
+            "The legacy ping is longer than expected",
+        ));
+    }
+    let mut part = [0u8; MAX_LEGACY_PLUGIN_MESSAGE_LENGTH];
+    reader.read_exact(&mut part[..length]).await?;
+    Ok(())
+}
+
+// The answer to a legacy ping in the "§1" format of 1.4 and newer
+pub struct LegacyPingResponse(pub StatusSummary);
+
+impl LegacyPingResponse {
+    pub async fn write<W: AsyncWriteExt + Unpin>(&self, writer: &mut W) -> tokio::io::Result<()> {
+        // The fields are separated by null characters, so they can't contain any themselves
+        let StatusSummary {
+            version_name,
+            motd,
+            online_players,
+            max_players,
+        } = &self.0;
+        let version_name = version_name.replace('\0', "");
+        let fields = |motd: &str| {
+            format!(
+                "§1\0{LEGACY_PING_PROTOCOL}\0{version_name}\0{motd}\0{online_players}\0{max_players}"
+            )
+        };
+
+        let mut motd = motd.replace('\0', "");
+        let mut response = fields(&motd);
+        // The description is shortened until the response fits
+        while response.encode_utf16().count() > MAX_LEGACY_RESPONSE_LENGTH && motd.pop().is_some() {
+            response = fields(&motd);
+        }
+        let response = response.encode_utf16().collect::<Vec<_>>();
+        let length = u16::try_from(response.len()).map_err(|_| {
+            tokio::io::Error::new(
+                tokio::io::ErrorKind::InvalidData,
+                "The legacy ping response is too long",
+            )
+        })?;
+
+        let mut packet = Vec::with_capacity(3 + response.len() * 2);
+        packet.push(LEGACY_KICK_ID);
+        packet.extend_from_slice(&length.to_be_bytes());
+        for unit in response {
+            packet.extend_from_slice(&unit.to_be_bytes());
+        }
+        writer.write_all(&packet).await
+    }
+}
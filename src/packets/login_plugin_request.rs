@@ -0,0 +1,57 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    packets::{Packet, id::Managed, packet_read::ReadPacket, packet_write::WritePacket},
+    types::{MCData, MCString, VarInt},
+};
+
+// A login plugin request on any channel, as sent by a backend
+pub struct LoginPluginRequest {
+    pub message_id: VarInt,
+    pub channel: MCString<32767>,
+    pub data: Vec<u8>,
+}
+
+impl Packet<Managed> for LoginPluginRequest {
+    const PACKET_ID: Managed = Managed(0x04);
+
+    fn byte_size(&self) -> usize {
+        self.message_id.byte_size() + self.channel.byte_size() + self.data.len()
+    }
+}
+
+impl ReadPacket for LoginPluginRequest {
+    async fn read<R: AsyncReadExt + Unpin>(
+        reader: &mut R,
+        expected_length: VarInt,
+    ) -> tokio::io::Result<Self> {
+        let message_id = VarInt::read(reader).await?;
+        let channel = MCString::read(reader).await?;
+
+        // The rest of the packet is the plugin specific data
+        let remaining_bytes = (*expected_length as usize)
+            .checked_sub(message_id.byte_size() + channel.byte_size())
+            .ok_or_else(|| {
+                tokio::io::Error::new(
+                    tokio::io::ErrorKind::InvalidData,
+                    "The Login Plugin Request Packet is shorter than its header",
+                )
+            })?;
+        let mut data = vec![0u8; remaining_bytes];
+        reader.read_exact(&mut data).await?;
+
+        Ok(LoginPluginRequest {
+            message_id,
+            channel,
+            data,
+        })
+    }
+}
+
+impl WritePacket for LoginPluginRequest {
+    async fn write<W: AsyncWriteExt + Unpin>(&self, writer: &mut W) -> tokio::io::Result<()> {
+        self.message_id.write(writer).await?;
+        self.channel.write(writer).await?;
+        writer.write_all(&self.data).await
+    }
+}
@@ -1,36 +1,99 @@
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use crate::{
-    packets::{Packet, id::Managed, packet_read::ReadPacket, packet_write::WritePacket},
-    types::{MCData, MCString, VarInt},
+    packets::{
+        Packet,
+        id::Managed,
+        packet_read::ReadPacket,
+        packet_write::WritePacket,
+        protocol::{LoginStartLayout, ProtocolVersion},
+    },
+    types::{MCData, MCString, PlayerKey, Uuid, VarInt},
 };
 
 pub struct LoginStart {
     pub username: MCString<16>,
-    // It seems the UUID is only sent in later versions?
+    // Everything after the username, kept as is so it reaches the backend unchanged
+    raw_remaining_data: Vec<u8>,
+}
+
+// The fields newer clients send after the username
+pub struct LoginStartFields {
+    // Only sent by 1.19 - 1.19.2 clients
+    pub player_key: Option<PlayerKey>,
+    // Only sent by 1.19.1 and newer clients
+    pub player_uuid: Option<Uuid>,
+}
+
+impl LoginStart {
+    // Reads the fields after the username according to the protocol version of the client
+    pub async fn fields(&self, protocol: i32) -> tokio::io::Result<LoginStartFields> {
+        let reader = &mut &self.raw_remaining_data[..];
+        let layout = ProtocolVersion::get(protocol)
+            .map_or(LoginStartLayout::Username, |version| version.login_start);
+
+        let player_key = match layout {
+            LoginStartLayout::Key | LoginStartLayout::KeyAndUuid
+                if reader.read_u8().await? == 0x01 =>
+            {
+                Some(PlayerKey::read(reader).await?)
+            }
+            _ => None,
+        };
+
+        let player_uuid = match layout {
+            LoginStartLayout::Username | LoginStartLayout::Key => None,
+            LoginStartLayout::KeyAndUuid | LoginStartLayout::OptionalUuid
+                if reader.read_u8().await? != 0x01 =>
+            {
+                None
+            }
+            _ => Some(Uuid::read(reader).await?),
+        };
+
+        Ok(LoginStartFields {
+            player_key,
+            player_uuid,
+        })
+    }
 }
 
 impl Packet<Managed> for LoginStart {
     const PACKET_ID: Managed = Managed(0x00);
 
     fn byte_size(&self) -> usize {
-        self.username.byte_size()
+        self.username.byte_size() + self.raw_remaining_data.len()
     }
 }
 
 impl ReadPacket for LoginStart {
     async fn read<R: AsyncReadExt + Unpin>(
         reader: &mut R,
-        _expected_length: VarInt,
+        expected_length: VarInt,
     ) -> tokio::io::Result<Self> {
+        let username = MCString::read(reader).await?;
+
+        let remaining_bytes = (*expected_length as usize)
+            .checked_sub(username.byte_size())
+            .ok_or_else(|| {
+                tokio::io::Error::new(
+                    tokio::io::ErrorKind::InvalidData,
+                    "The Login Start Packet is shorter than its username",
+                )
+            })?;
+        let mut raw_remaining_data = vec![0u8; remaining_bytes];
+        reader.read_exact(&mut raw_remaining_data).await?;
+
         Ok(LoginStart {
-            username: MCString::read(reader).await?,
+            username,
+            raw_remaining_data,
         })
     }
 }
 
 impl WritePacket for LoginStart {
     async fn write<W: AsyncWriteExt + Unpin>(&self, writer: &mut W) -> tokio::io::Result<()> {
-        self.username.write(writer).await
+        self.username.write(writer).await?;
+        writer.write_all(&self.raw_remaining_data).await
     }
 }
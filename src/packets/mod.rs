@@ -1,17 +1,30 @@
 mod handshake;
-pub use handshake::Handshake;
+pub use handshake::{BUNGEEGUARD_TOKEN_PROPERTY, BungeeForwardingData, Handshake};
 
 mod login_start;
 pub use login_start::LoginStart;
 
+mod login_plugin_request;
+pub use login_plugin_request::LoginPluginRequest;
+
 mod velocity_plugin_request;
-pub use velocity_plugin_request::VelocityLoginPluginRequest;
+pub use velocity_plugin_request::{VELOCITY_PLAYER_INFO_CHANNEL, VelocityLoginPluginRequest};
 
 mod velocity_plugin_response;
-pub use velocity_plugin_response::VelocityLoginPluginResponse;
+pub use velocity_plugin_response::{
+    MODERN_FORWARDING_DEFAULT, MODERN_FORWARDING_MAX_VERSION, MODERN_FORWARDING_WITH_KEY,
+    MODERN_FORWARDING_WITH_KEY_V2, MODERN_LAZY_SESSION, PlayerInfo, VelocityLoginPluginAnswer,
+    VelocityLoginPluginResponse,
+};
 
 mod disconnect;
-pub use disconnect::{Disconnect, PlayDisconnect};
+pub use disconnect::{ConfigurationDisconnect, Disconnect, PlayDisconnect};
+
+mod status;
+pub use status::{Ping, StatusRequest, StatusResponse, StatusSummary};
+
+mod legacy_ping;
+pub use legacy_ping::{LegacyPingResponse, is_legacy_ping, read_legacy_ping};
 
 mod generic;
 pub use generic::GenericPacket;
@@ -19,6 +32,8 @@ pub use generic::GenericPacket;
 pub mod packet_read;
 pub mod packet_write;
 
+pub mod protocol;
+
 pub mod id {
     pub trait AsId {}
 
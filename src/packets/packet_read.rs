@@ -22,7 +22,11 @@ where
     Self: AsyncReadExt + Unpin + Sized,
     Id: AsId,
 {
-    async fn read_packet<P: ReadPacket + Packet<Id>>(&mut self) -> Result<P, ReadPacketError>;
+    // Reads a packet, refusing packets longer than the given length
+    async fn read_packet_limited<P: ReadPacket + Packet<Id>>(
+        &mut self,
+        max_length: usize,
+    ) -> Result<P, ReadPacketError>;
 }
 
 #[allow(dead_code)]
@@ -72,20 +76,34 @@ impl From<ReadPacketError> for ReadVersionedPacketError {
     }
 }
 
+// The longest packet the vanilla server accepts, as its length is limited to a 3 byte VarInt
+pub const MAX_PACKET_LENGTH: i32 = (1 << 21) - 1;
+
 async fn read_packet_general<R: AsyncReadExt + Unpin, P: ReadPacket + Packet<Id>, Id: AsId>(
     reader: &mut R,
     packet_id: Option<u8>,
+    max_length: usize,
 ) -> Result<P, ReadPacketError> {
     let packet_length = VarInt::read(reader).await?;
 
+    // Don't allocate whatever the peer claims the packet length to be, the packet id needs at least one byte
+    let min_packet_length = packet_id.map_or(0, |_| 1);
+    let max_packet_length = max_length.min(MAX_PACKET_LENGTH as usize) as i32;
+    if !(min_packet_length..=max_packet_length).contains(&*packet_length) {
+        return Err(ReadPacketError::Io(tokio::io::Error::new(
+            tokio::io::ErrorKind::InvalidData,
+            format!("Packet length {} is out of range", *packet_length),
+        )));
+    }
+
     // Account for the packet ID if specified
     if let Some(packet_id) = packet_id {
         let read_packet_id = reader.read_u8().await?;
         if read_packet_id != packet_id {
             // Read the rest into a buffer to give the generic packet the entire data
-            let mut buffer = vec![0u8; *packet_length as usize];
-            buffer.push(read_packet_id);
-            reader.read_exact(&mut buffer).await?;
+            let mut buffer = vec![0u8; (*packet_length as usize).max(1)];
+            buffer[0] = read_packet_id;
+            reader.read_exact(&mut buffer[1..]).await?;
 
             return Err(ReadPacketError::InvalidPacketId {
                 expected: packet_id,
@@ -120,14 +138,20 @@ async fn read_packet_general<R: AsyncReadExt + Unpin, P: ReadPacket + Packet<Id>
 }
 
 impl<R: AsyncReadExt + Unpin + Sized> ReadPacketExt<Manual> for R {
-    async fn read_packet<P: ReadPacket + Packet<Manual>>(&mut self) -> Result<P, ReadPacketError> {
-        read_packet_general(self, None).await
+    async fn read_packet_limited<P: ReadPacket + Packet<Manual>>(
+        &mut self,
+        max_length: usize,
+    ) -> Result<P, ReadPacketError> {
+        read_packet_general(self, None, max_length).await
     }
 }
 
 impl<R: AsyncReadExt + Unpin + Sized> ReadPacketExt<Managed> for R {
-    async fn read_packet<P: ReadPacket + Packet<Managed>>(&mut self) -> Result<P, ReadPacketError> {
-        read_packet_general(self, Some(*P::PACKET_ID)).await
+    async fn read_packet_limited<P: ReadPacket + Packet<Managed>>(
+        &mut self,
+        max_length: usize,
+    ) -> Result<P, ReadPacketError> {
+        read_packet_general(self, Some(*P::PACKET_ID), max_length).await
     }
 }
 
@@ -139,7 +163,7 @@ impl<R: AsyncReadExt + Unpin + Sized> ReadVersionedPacketExt<VersionDependent> f
         let packet_id = P::PACKET_ID
             .get(protocol)
             .ok_or(ReadVersionedPacketError::UnknownVersionedPacketId { protocol })?;
-        let packet = read_packet_general(self, Some(packet_id)).await?;
+        let packet = read_packet_general(self, Some(packet_id), MAX_PACKET_LENGTH as usize).await?;
         Ok(packet)
     }
 }
@@ -0,0 +1,119 @@
+use std::sync::LazyLock;
+
+use serde::Deserialize;
+
+// The known versions, new versions only need an entry in the table
+static TABLE: LazyLock<ProtocolTable> = LazyLock::new(|| {
+    let table = toml::from_str::<ProtocolTable>(include_str!("protocols.toml"))
+        .expect("The embedded protocol table is invalid");
+    assert!(
+        table.versions.is_sorted_by_key(|version| version.protocol),
+        "The embedded protocol table is not ordered by protocol number"
+    );
+    table
+});
+
+#[derive(Deserialize)]
+struct ProtocolTable {
+    #[serde(default)]
+    unknown: Vec<UnknownProtocols>,
+    versions: Vec<ProtocolVersion>,
+}
+
+// Protocol numbers that don't belong to the version before them
+#[derive(Deserialize)]
+struct UnknownProtocols {
+    from: i32,
+    to: i32,
+}
+
+#[derive(Deserialize)]
+pub struct ProtocolVersion {
+    pub protocol: i32,
+    pub name: String,
+    pub login_start: LoginStartLayout,
+    pub text_components: TextComponentFormat,
+    pub play: PlayPacketIds,
+    pub configuration: Option<ConfigurationPacketIds>,
+}
+
+// What follows the username in the Login Start packet
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginStartLayout {
+    Username,
+    Key,
+    KeyAndUuid,
+    OptionalUuid,
+    Uuid,
+}
+
+impl LoginStartLayout {
+    // Clients that don't send a chat signing key during login use chat sessions instead
+    pub fn uses_chat_sessions(self) -> bool {
+        matches!(
+            self,
+            LoginStartLayout::OptionalUuid | LoginStartLayout::Uuid
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextComponentFormat {
+    Json,
+    Nbt,
+}
+
+#[derive(Deserialize)]
+pub struct PlayPacketIds {
+    pub disconnect: u8,
+    // Sends the client back to the configuration state, since 1.20.2
+    pub start_configuration: Option<u8>,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigurationPacketIds {
+    pub disconnect: u8,
+    // Moves the client on to the play state
+    pub finish: u8,
+}
+
+impl ProtocolVersion {
+    // The version a protocol number belongs to, protocols between two versions belong to the older one
+    // Protocols newer than all known versions may have changed anything, so they have none
+    pub fn get(protocol: i32) -> Option<&'static ProtocolVersion> {
+        let versions = &TABLE.versions;
+        if protocol > versions.last()?.protocol
+            || TABLE
+                .unknown
+                .iter()
+                .any(|unknown| (unknown.from..=unknown.to).contains(&protocol))
+        {
+            return None;
+        }
+
+        let index = versions.partition_point(|version| version.protocol <= protocol);
+        versions.get(index.checked_sub(1)?)
+    }
+
+    pub fn known() -> &'static [ProtocolVersion] {
+        &TABLE.versions
+    }
+}
+
+// Displays a protocol number along with the name of its version, like "1.8 - 1.8.9 (protocol 47)"
+pub struct VersionName(pub i32);
+
+impl std::fmt::Display for VersionName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match TABLE
+            .versions
+            .iter()
+            .find(|version| version.protocol == self.0)
+        {
+            Some(version) => write!(f, "{} (protocol {})", version.name, self.0),
+            None => write!(f, "an unknown version (protocol {})", self.0),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    packets::{Packet, id::Managed, packet_read::ReadPacket, packet_write::WritePacket},
+    status_rewrite::plain_text,
+    types::{MCData, MCString, VarInt},
+};
+
+// Sent by the client right after a handshake for the status state, it has no fields
+pub struct StatusRequest;
+
+impl Packet<Managed> for StatusRequest {
+    const PACKET_ID: Managed = Managed(0x00);
+
+    fn byte_size(&self) -> usize {
+        0
+    }
+}
+
+impl ReadPacket for StatusRequest {
+    async fn read<R: AsyncReadExt + Unpin>(
+        _reader: &mut R,
+        _expected_length: VarInt,
+    ) -> tokio::io::Result<Self> {
+        Ok(StatusRequest)
+    }
+}
+
+impl WritePacket for StatusRequest {
+    async fn write<W: AsyncWriteExt + Unpin>(&self, _writer: &mut W) -> tokio::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct StatusResponse {
+    pub json: MCString<32767>,
+}
+
+impl StatusResponse {
+    // A status made up by the proxy, using the protocol of the client so it is not shown as incompatible
+    pub fn synthesized(
+        protocol: i32,
+        version_name: &str,
+        motd: &str,
+        online_players: u32,
+        max_players: u32,
+    ) -> Result<Self, &'static str> {
+        let json = serde_json::json!({
+            "version": {
+                "name": version_name,
+                "protocol": protocol,
+            },
+            "players": {
+                "max": max_players,
+                "online": online_players,
+            },
+            "description": {
+                "text": motd,
+            },
+        });
+
+        Ok(Self {
+            json: MCString::new(json.to_string())?,
+        })
+    }
+}
+
+// What old clients and monitoring tools can show of a status response
+pub struct StatusSummary {
+    pub version_name: String,
+    pub motd: String,
+    pub online_players: i64,
+    pub max_players: i64,
+}
+
+impl StatusResponse {
+    pub fn summary(&self) -> Result<StatusSummary, &'static str> {
+        let json = serde_json::from_str::<serde_json::Value>(self.json.as_str())
+            .map_err(|_| "The status response is not valid JSON")?;
+
+        let players = |count| {
+            json.get("players")
+                .and_then(|players| players.get(count))
+                .and_then(serde_json::Value::as_i64)
+                .unwrap_or_default()
+        };
+
+        Ok(StatusSummary {
+            version_name: json
+                .get("version")
+                .and_then(|version| version.get("name"))
+                .and_then(serde_json::Value::as_str)
+                .unwrap_or_default()
+                .to_owned(),
+            motd: json.get("description").map(plain_text).unwrap_or_default(),
+            online_players: players("online"),
+            max_players: players("max"),
+        })
+    }
+}
+
+impl Packet<Managed> for StatusResponse {
+    const PACKET_ID: Managed = Managed(0x00);
+
+    fn byte_size(&self) -> usize {
+        self.json.byte_size()
+    }
+}
+
+impl ReadPacket for StatusResponse {
+    async fn read<R: AsyncReadExt + Unpin>(
+        reader: &mut R,
+        _expected_length: VarInt,
+    ) -> tokio::io::Result<Self> {
+        Ok(StatusResponse {
+            json: MCString::read(reader).await?,
+        })
+    }
+}
+
+impl WritePacket for StatusResponse {
+    async fn write<W: AsyncWriteExt + Unpin>(&self, writer: &mut W) -> tokio::io::Result<()> {
+        self.json.write(writer).await
+    }
+}
+
+// The ping request of the client and the pong response of the server look the same
+pub struct Ping {
+    pub payload: i64,
+}
+
+impl Packet<Managed> for Ping {
+    const PACKET_ID: Managed = Managed(0x01);
+
+    fn byte_size(&self) -> usize {
+        8 // i64
+    }
+}
+
+impl ReadPacket for Ping {
+    async fn read<R: AsyncReadExt + Unpin>(
+        reader: &mut R,
+        _expected_length: VarInt,
+    ) -> tokio::io::Result<Self> {
+        Ok(Ping {
+            payload: reader.read_i64().await?,
+        })
+    }
+}
+
+impl WritePacket for Ping {
+    async fn write<W: AsyncWriteExt + Unpin>(&self, writer: &mut W) -> tokio::io::Result<()> {
+        writer.write_i64(self.payload).await
+    }
+}
@@ -5,14 +5,18 @@ use crate::{
     types::{MCData, MCString, VarInt},
 };
 
+pub const VELOCITY_PLAYER_INFO_CHANNEL: &str = "velocity:player_info";
+
 pub struct VelocityLoginPluginRequest {
     connection_id: VarInt,
+    max_forwarding_version: u8,
 }
 
 impl VelocityLoginPluginRequest {
-    pub fn new(connection_id: i32) -> Self {
+    pub fn new(connection_id: i32, max_forwarding_version: u8) -> Self {
         Self {
             connection_id: VarInt::new(connection_id).unwrap(),
+            max_forwarding_version,
         }
     }
 }
@@ -23,7 +27,7 @@ impl Packet<Managed> for VelocityLoginPluginRequest {
     fn byte_size(&self) -> usize {
         self.connection_id.byte_size() // Message ID
             + {
-                let channel = "velocity:player_info";
+                let channel = VELOCITY_PLAYER_INFO_CHANNEL;
                 VarInt::new(channel.len() as i32).unwrap().byte_size() + channel.len()
             } // Channel (VarInt length + string)
             + 1 // Protocol version u8
@@ -33,10 +37,10 @@ impl Packet<Managed> for VelocityLoginPluginRequest {
 impl WritePacket for VelocityLoginPluginRequest {
     async fn write<W: AsyncWriteExt + Unpin>(&self, mut writer: &mut W) -> tokio::io::Result<()> {
         self.connection_id.write(&mut writer).await?; // Message ID
-        MCString::<32767>::new(String::from_utf8_lossy(b"velocity:player_info").into_owned())
+        MCString::<32767>::new(VELOCITY_PLAYER_INFO_CHANNEL.to_owned())
             .unwrap()
             .write(&mut writer)
             .await?; // Channel
-        writer.write_u8(0x01).await // Protocol version
+        writer.write_u8(self.max_forwarding_version).await // Protocol version
     }
 }
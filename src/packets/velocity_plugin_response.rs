@@ -1,31 +1,225 @@
 use hmac::{Hmac, Mac};
 use sha2::Sha256;
-use tokio::io::{AsyncReadExt, BufReader};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
 
 use crate::{
-    packets::{Packet, id::Managed, packet_read::ReadPacket},
-    types::{MCData, MCString, Uuid, VarInt},
+    packets::{Packet, id::Managed, packet_read::ReadPacket, packet_write::WritePacket},
+    types::{MCData, MCString, PlayerKey, Uuid, VarInt},
 };
 
+// The versions of the velocity:player_info payload
+pub const MODERN_FORWARDING_DEFAULT: u8 = 1;
+pub const MODERN_FORWARDING_WITH_KEY: u8 = 2;
+pub const MODERN_FORWARDING_WITH_KEY_V2: u8 = 3;
+pub const MODERN_LAZY_SESSION: u8 = 4;
+pub const MODERN_FORWARDING_MAX_VERSION: u8 = MODERN_LAZY_SESSION;
+
 pub struct VelocityLoginPluginResponse {
     pub connection_id: VarInt,
-    pub version: VarInt,
     pub signature: [u8; 32],
     raw_remaining_data: Vec<u8>, // Store any remaining data again for validation
+    pub player_info: PlayerInfo,
+}
+
+// What a client can answer the velocity:player_info request with
+pub enum VelocityLoginPluginAnswer {
+    Forwarded(Box<VelocityLoginPluginResponse>),
+    // The request was answered without a payload, as Velocity did not handle it and the client did not understand it
+    NotUnderstood { connection_id: VarInt },
+}
+
+impl VelocityLoginPluginAnswer {
+    pub fn connection_id(&self) -> VarInt {
+        match self {
+            VelocityLoginPluginAnswer::Forwarded(response) => response.connection_id,
+            VelocityLoginPluginAnswer::NotUnderstood { connection_id } => *connection_id,
+        }
+    }
+}
+
+// The data velocity forwards about a player
+pub struct PlayerInfo {
+    pub version: VarInt,
     pub client_address: MCString<32767>,
     pub player_uuid: Uuid,
     pub username: MCString<16>,
-    pub properties_length: VarInt,
     pub properties: Vec<Property>,
+    // Only present in MODERN_FORWARDING_WITH_KEY and MODERN_FORWARDING_WITH_KEY_V2
+    pub player_key: Option<PlayerKey>,
+    // Only present in MODERN_FORWARDING_WITH_KEY_V2, if the key is linked to a player
+    pub signer_uuid: Option<Uuid>,
+}
+
+impl PlayerInfo {
+    // Picks the forwarding version to answer a request for at most the requested version with, like Velocity does
+    // The key versions are not backwards compatible, so everything that can't be provided falls back to the default
+    pub fn for_requested_version(self, requested_version: u8) -> Self {
+        if *self.version <= requested_version as i32 {
+            return self;
+        }
+
+        PlayerInfo {
+            version: VarInt::new(MODERN_FORWARDING_DEFAULT as i32).unwrap(),
+            player_key: None,
+            signer_uuid: None,
+            ..self
+        }
+    }
+
+    fn has_player_key(&self) -> bool {
+        (MODERN_FORWARDING_WITH_KEY as i32..MODERN_LAZY_SESSION as i32).contains(&*self.version)
+    }
+
+    fn has_signer_uuid(&self) -> bool {
+        (MODERN_FORWARDING_WITH_KEY_V2 as i32..MODERN_LAZY_SESSION as i32).contains(&*self.version)
+    }
+}
+
+impl MCData for PlayerInfo {
+    async fn read<R: AsyncReadExt + Unpin>(reader: &mut R) -> tokio::io::Result<Self> {
+        let version = VarInt::read(reader).await?;
+
+        if !(MODERN_FORWARDING_DEFAULT as i32..=MODERN_FORWARDING_MAX_VERSION as i32)
+            .contains(&*version)
+        {
+            return Err(tokio::io::Error::new(
+                tokio::io::ErrorKind::InvalidData,
+                format!("Unsupported forwarding version {}", *version),
+            ));
+        }
+
+        let client_address = MCString::read(reader).await?;
+
+        let player_uuid = Uuid::read(reader).await?;
+
+        let username = MCString::read(reader).await?;
+
+        let properties_length = VarInt::read(reader).await?;
+
+        if *properties_length < 0 {
+            return Err(tokio::io::Error::new(
+                tokio::io::ErrorKind::InvalidData,
+                format!("Property count {} is invalid", *properties_length),
+            ));
+        }
+
+        // Only grows with the properties that are actually there, not the count the peer claims
+        let mut properties = Vec::new();
+        for _ in 0..*properties_length {
+            properties.push(Property::read(reader).await?);
+        }
+
+        let mut player_info = PlayerInfo {
+            version,
+            client_address,
+            player_uuid,
+            username,
+            properties,
+            player_key: None,
+            signer_uuid: None,
+        };
+
+        if player_info.has_player_key() {
+            player_info.player_key = Some(PlayerKey::read(reader).await?);
+        }
+
+        if player_info.has_signer_uuid() && reader.read_u8().await? == 0x01 {
+            player_info.signer_uuid = Some(Uuid::read(reader).await?);
+        }
+
+        Ok(player_info)
+    }
+
+    async fn write<W>(&self, writer: &mut W) -> tokio::io::Result<()>
+    where
+        W: AsyncWriteExt + Unpin,
+    {
+        self.version.write(writer).await?;
+        self.client_address.write(writer).await?;
+        self.player_uuid.write(writer).await?;
+        self.username.write(writer).await?;
+
+        VarInt::new(self.properties.len() as i32)
+            .map_err(|e| tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, e))?
+            .write(writer)
+            .await?;
+        for property in &self.properties {
+            property.write(writer).await?;
+        }
+
+        if self.has_player_key() {
+            let Some(player_key) = &self.player_key else {
+                return Err(tokio::io::Error::new(
+                    tokio::io::ErrorKind::InvalidData,
+                    "This forwarding version requires a player key",
+                ));
+            };
+            player_key.write(writer).await?;
+        }
+
+        if self.has_signer_uuid() {
+            match &self.signer_uuid {
+                Some(signer_uuid) => {
+                    writer.write_u8(0x01).await?;
+                    signer_uuid.write(writer).await?;
+                }
+                None => writer.write_u8(0x00).await?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn byte_size(&self) -> usize {
+        self.version.byte_size() // version
+        + self.client_address.byte_size() // Client Address
+        + self.player_uuid.byte_size() // Player ID
+        + self.username.byte_size() // Username
+        + VarInt::new(self.properties.len() as i32).unwrap().byte_size() // Length of properties array
+        + self.properties.iter().fold(0usize, |acc, e| acc + e.byte_size())
+        + if self.has_player_key() { self.player_key.as_ref().map_or(0, |key| key.byte_size()) } else { 0 } // Player key
+        + if self.has_signer_uuid() { 1 + self.signer_uuid.map_or(0, |uuid| uuid.byte_size()) } else { 0 } // Maybe a signer uuid
+    }
 }
 
+#[derive(Clone)]
 pub struct Property {
     pub name: MCString<32767>,
     pub value: MCString<32767>,
     pub signature: Option<MCString<32767>>,
 }
 
-impl Property {
+impl MCData for Property {
+    async fn read<R: AsyncReadExt + Unpin>(reader: &mut R) -> tokio::io::Result<Self> {
+        let name = MCString::read(reader).await?;
+        let value = MCString::read(reader).await?;
+        let signature = if reader.read_u8().await? == 0x01 {
+            Some(MCString::read(reader).await?)
+        } else {
+            None
+        };
+        Ok(Property {
+            name,
+            value,
+            signature,
+        })
+    }
+
+    async fn write<W>(&self, writer: &mut W) -> tokio::io::Result<()>
+    where
+        W: AsyncWriteExt + Unpin,
+    {
+        self.name.write(writer).await?;
+        self.value.write(writer).await?;
+        match &self.signature {
+            Some(signature) => {
+                writer.write_u8(0x01).await?;
+                signature.write(writer).await
+            }
+            None => writer.write_u8(0x00).await,
+        }
+    }
+
     fn byte_size(&self) -> usize {
         self.name.byte_size() // name
         + self.value.byte_size() // value
@@ -35,13 +229,34 @@ impl Property {
 }
 
 impl VelocityLoginPluginResponse {
-    pub fn validate(&self, secret: &str) -> bool {
-        Hmac::<Sha256>::new_from_slice(secret.as_bytes())
-            .map(|mut hmac| {
-                hmac.update(&self.raw_remaining_data);
-                hmac.verify((&self.signature).into()).is_ok()
-            })
-            .unwrap_or(false)
+    // Creates a response to a backend, signed like Velocity would
+    pub async fn signed(
+        connection_id: VarInt,
+        player_info: PlayerInfo,
+        secret: &str,
+    ) -> tokio::io::Result<Self> {
+        let mut raw_remaining_data = Vec::with_capacity(player_info.byte_size());
+        player_info.write(&mut raw_remaining_data).await?;
+
+        let mut hmac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .map_err(|e| tokio::io::Error::new(tokio::io::ErrorKind::InvalidInput, e))?;
+        hmac.update(&raw_remaining_data);
+
+        Ok(VelocityLoginPluginResponse {
+            connection_id,
+            signature: hmac.finalize().into_bytes().into(),
+            raw_remaining_data,
+            player_info,
+        })
+    }
+
+    // Tries the given secrets in order and returns the key of the first one the response is signed with
+    pub fn validate<'a, K>(&self, secrets: impl IntoIterator<Item = (K, &'a str)>) -> Option<K> {
+        secrets.into_iter().find_map(|(key, secret)| {
+            let mut hmac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+            hmac.update(&self.raw_remaining_data);
+            hmac.verify((&self.signature).into()).ok().map(|_| key)
+        })
     }
 }
 
@@ -52,16 +267,33 @@ impl Packet<Managed> for VelocityLoginPluginResponse {
         self.connection_id.byte_size() // Message ID
         + 1 // Has Payload (boolean)
         + 32 // Signature
-        + self.version.byte_size() // version
-        + self.client_address.byte_size() // Client Address
-        + self.player_uuid.byte_size() // Player ID
-        + self.username.byte_size() // Username
-        + self.properties_length.byte_size() // Length of properties array
-        + self.properties.iter().fold(0usize, |acc, e| acc + e.byte_size())
+        + self.raw_remaining_data.len() // The forwarded player info
+    }
+}
+
+impl WritePacket for VelocityLoginPluginResponse {
+    async fn write<W: AsyncWriteExt + Unpin>(&self, writer: &mut W) -> tokio::io::Result<()> {
+        self.connection_id.write(writer).await?; // Message ID
+        writer.write_u8(0x01).await?; // Has Payload
+        writer.write_all(&self.signature).await?; // Signature
+        writer.write_all(&self.raw_remaining_data).await // Player info
+    }
+}
+
+impl Packet<Managed> for VelocityLoginPluginAnswer {
+    const PACKET_ID: Managed = Managed(0x02);
+
+    fn byte_size(&self) -> usize {
+        match self {
+            VelocityLoginPluginAnswer::Forwarded(response) => response.byte_size(),
+            VelocityLoginPluginAnswer::NotUnderstood { connection_id } => {
+                connection_id.byte_size() + 1 // Message ID + Has Payload (boolean)
+            }
+        }
     }
 }
 
-impl ReadPacket for VelocityLoginPluginResponse {
+impl ReadPacket for VelocityLoginPluginAnswer {
     async fn read<R: AsyncReadExt + Unpin>(
         reader: &mut R,
         expected_length: VarInt,
@@ -71,10 +303,7 @@ impl ReadPacket for VelocityLoginPluginResponse {
         let has_payload = reader.read_u8().await? == 0x01;
 
         if !has_payload {
-            return Err(tokio::io::Error::new(
-                tokio::io::ErrorKind::InvalidData,
-                "A Login Plugin Response Packet is expected to have a payload, but none was found",
-            ));
+            return Ok(VelocityLoginPluginAnswer::NotUnderstood { connection_id });
         }
 
         // Start of Custom Payload
@@ -83,48 +312,26 @@ impl ReadPacket for VelocityLoginPluginResponse {
 
         // Read all the extra data into a buffer for validation later
         let bytes_read_so_far = connection_id.byte_size() + 1 + 32; // connection_id + has_payload + signature
-        let remaining_bytes = *expected_length as usize - bytes_read_so_far;
+        let remaining_bytes = (*expected_length as usize)
+            .checked_sub(bytes_read_so_far)
+            .ok_or_else(|| {
+                tokio::io::Error::new(
+                    tokio::io::ErrorKind::InvalidData,
+                    "The Login Plugin Response Packet is too short to contain a signature",
+                )
+            })?;
         let mut raw_remaining_data = vec![0u8; remaining_bytes];
 
         reader.read_exact(&mut raw_remaining_data).await?;
-        let reader = &mut BufReader::new(&raw_remaining_data[..]);
-
-        let version = VarInt::read(reader).await?;
-
-        let client_address = MCString::read(reader).await?;
-
-        let player_uuid = Uuid::read(reader).await?;
-
-        let username = MCString::read(reader).await?;
+        let player_info = PlayerInfo::read(&mut BufReader::new(&raw_remaining_data[..])).await?;
 
-        let properties_length = VarInt::read(reader).await?;
-
-        let mut properties = Vec::with_capacity(*properties_length as usize);
-        for _ in 0..*properties_length {
-            let name = MCString::read(reader).await?;
-            let value = MCString::read(reader).await?;
-            let signature = if reader.read_u8().await? == 0x01 {
-                Some(MCString::read(reader).await?)
-            } else {
-                None
-            };
-            properties.push(Property {
-                name,
-                value,
+        Ok(VelocityLoginPluginAnswer::Forwarded(Box::new(
+            VelocityLoginPluginResponse {
+                connection_id,
                 signature,
-            });
-        }
-
-        Ok(VelocityLoginPluginResponse {
-            connection_id,
-            version,
-            signature,
-            raw_remaining_data,
-            client_address,
-            player_uuid,
-            username,
-            properties_length,
-            properties,
-        })
+                raw_remaining_data,
+                player_info,
+            },
+        )))
     }
 }
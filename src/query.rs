@@ -0,0 +1,292 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{net::UdpSocket, sync::Semaphore};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace, warn};
+
+use crate::{
+    config::BackendConfig, connection::Connection, online_players::OnlinePlayers,
+    packets::StatusSummary, status_cache::StatusCache,
+};
+
+// Every query request starts with these bytes
+const QUERY_MAGIC: [u8; 2] = [0xFE, 0xFD];
+
+const HANDSHAKE_TYPE: u8 = 0x09;
+const STAT_TYPE: u8 = 0x00;
+
+// Full stat requests are padded to this length, basic stat requests are shorter
+const FULL_STAT_REQUEST_LENGTH: usize = 15;
+
+// Requests are much shorter, anything longer is cut off
+const MAX_QUERY_LENGTH: usize = 64;
+
+// Vanilla servers accept a challenge token for this long
+const CHALLENGE_TOKEN_LIFETIME: Duration = Duration::from_secs(30);
+
+// Every address gets its own token, so only this many are kept to protect the memory of this proxy
+const MAX_CHALLENGE_TOKENS: usize = 4096;
+
+// The fixed parts of the full stat response, which clients expect as they are
+const FULL_STAT_PADDING: &[u8] = b"splitnum\0\x80\0";
+const PLAYER_LIST_PADDING: &[u8] = b"\x01player_\0\0";
+
+// Stat requests answered at the same time, any more are dropped while the status of the backend is awaited
+const MAX_PENDING_STATS: usize = 16;
+
+// What vanilla servers report about themselves
+const GAME_TYPE: &str = "SMP";
+const GAME_ID: &str = "MINECRAFT";
+const MAP: &str = "world";
+
+// Answers the query protocol of server list sites and monitoring tools for a backend
+pub async fn listen_query(
+    socket: Arc<UdpSocket>,
+    backend: Arc<BackendConfig>,
+    status_cache: Arc<StatusCache>,
+    online_players: Arc<OnlinePlayers>,
+    cancel: CancellationToken,
+) {
+    let mut challenge_tokens = ChallengeTokens::default();
+    let mut request = [0u8; MAX_QUERY_LENGTH];
+    let pending_stats = Arc::new(Semaphore::new(MAX_PENDING_STATS));
+
+    loop {
+        let (length, address) = tokio::select! {
+            _ = cancel.cancelled() => {
+                trace!("Shutting down query listener of {}", backend.bind_address);
+                break;
+            }
+            receive_result = socket.recv_from(&mut request) => {
+                match receive_result {
+                    Ok(received) => received,
+                    Err(e) => {
+                        debug!("Failed to receive query: {e}");
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let response = match check_query(&request[..length], address, &mut challenge_tokens) {
+            Some(Query::Handshake(response)) => response,
+            Some(Query::Stat { response, full }) => {
+                let Ok(pending_stat) = pending_stats.clone().try_acquire_owned() else {
+                    debug!(
+                        "Dropping stat query from {address}, as too many are waiting for the status"
+                    );
+                    continue;
+                };
+
+                // The backend may have to be asked for its status, which must not hold up other queries
+                tokio::spawn({
+                    let socket = socket.clone();
+                    let backend = backend.clone();
+                    let status_cache = status_cache.clone();
+                    let online_players = online_players.clone();
+
+                    async move {
+                        let _pending_stat = pending_stat;
+                        if let Some(response) =
+                            answer_stat(response, full, &backend, &status_cache, &online_players)
+                                .await
+                        {
+                            send_response(&socket, &response, address).await;
+                        }
+                    }
+                });
+                continue;
+            }
+            None => continue,
+        };
+
+        send_response(&socket, &response, address).await;
+    }
+}
+
+async fn send_response(socket: &UdpSocket, response: &[u8], address: SocketAddr) {
+    if let Err(e) = socket.send_to(response, address).await {
+        debug!("Failed to send query response to {address}: {e}");
+    }
+}
+
+// A query that passed the checks of the listener
+enum Query {
+    // The complete response
+    Handshake(Vec<u8>),
+    // The start of the response, the stats still have to be added
+    Stat { response: Vec<u8>, full: bool },
+}
+
+// Answers handshakes and verifies the challenge token of stat requests
+fn check_query(
+    request: &[u8],
+    address: SocketAddr,
+    challenge_tokens: &mut ChallengeTokens,
+) -> Option<Query> {
+    let [magic_0, magic_1, kind, session_id @ ..] = request.get(..7)? else {
+        return None;
+    };
+    if [*magic_0, *magic_1] != QUERY_MAGIC {
+        trace!("Ignoring query from {address} without the query magic");
+        return None;
+    }
+
+    // The session id is sent back as it is, so clients can match the responses to their requests
+    let mut response = vec![*kind];
+    response.extend_from_slice(session_id);
+
+    match *kind {
+        HANDSHAKE_TYPE => {
+            trace!("Answering query handshake of {address}");
+            let Some(token) = challenge_tokens.issue(address) else {
+                warn!("Failed to generate a query challenge token");
+                return None;
+            };
+            push_string(&mut response, &token.to_string());
+            Some(Query::Handshake(response))
+        }
+        STAT_TYPE => {
+            let token = i32::from_be_bytes(request.get(7..11)?.try_into().unwrap());
+            if !challenge_tokens.verify(address, token) {
+                debug!("Ignoring query from {address} with an invalid challenge token");
+                return None;
+            }
+
+            let full = request.len() == FULL_STAT_REQUEST_LENGTH;
+            if full {
+                trace!("Answering full stat query of {address}");
+            } else {
+                trace!("Answering basic stat query of {address}");
+            }
+            Some(Query::Stat { response, full })
+        }
+        kind => {
+            trace!("Ignoring query from {address} with unknown type {kind:x}");
+            None
+        }
+    }
+}
+
+async fn answer_stat(
+    mut response: Vec<u8>,
+    full: bool,
+    backend: &BackendConfig,
+    status_cache: &StatusCache,
+    online_players: &OnlinePlayers,
+) -> Option<Vec<u8>> {
+    let status = Connection::unversioned_status(backend, status_cache).await?;
+    let summary = match status.summary() {
+        Ok(summary) => summary,
+        Err(e) => {
+            warn!("Failed to answer query: {e}");
+            return None;
+        }
+    };
+
+    if full {
+        push_full_stat(&mut response, &summary, backend, online_players);
+    } else {
+        push_basic_stat(&mut response, &summary, backend);
+    }
+    Some(response)
+}
+
+fn push_basic_stat(response: &mut Vec<u8>, summary: &StatusSummary, backend: &BackendConfig) {
+    push_string(response, &summary.motd);
+    push_string(response, GAME_TYPE);
+    push_string(response, MAP);
+    push_string(response, &summary.online_players.to_string());
+    push_string(response, &summary.max_players.to_string());
+    // Unlike everything else, the port is little endian
+    response.extend_from_slice(&backend.bind_address.port().to_le_bytes());
+    push_string(response, &backend.bind_address.ip().to_string());
+}
+
+fn push_full_stat(
+    response: &mut Vec<u8>,
+    summary: &StatusSummary,
+    backend: &BackendConfig,
+    online_players: &OnlinePlayers,
+) {
+    response.extend_from_slice(FULL_STAT_PADDING);
+
+    let values = [
+        ("hostname", summary.motd.clone()),
+        ("gametype", GAME_TYPE.to_owned()),
+        ("game_id", GAME_ID.to_owned()),
+        ("version", summary.version_name.clone()),
+        ("plugins", String::new()),
+        ("map", MAP.to_owned()),
+        ("numplayers", summary.online_players.to_string()),
+        ("maxplayers", summary.max_players.to_string()),
+        ("hostport", backend.bind_address.port().to_string()),
+        ("hostip", backend.bind_address.ip().to_string()),
+    ];
+    for (key, value) in values {
+        push_string(response, key);
+        push_string(response, &value);
+    }
+    push_string(response, "");
+
+    response.extend_from_slice(PLAYER_LIST_PADDING);
+    for name in online_players.names() {
+        push_string(response, &name);
+    }
+    push_string(response, "");
+}
+
+// Strings are null terminated and encoded as ISO-8859-1, other characters are replaced
+fn push_string(response: &mut Vec<u8>, string: &str) {
+    response.extend(
+        string
+            .chars()
+            .filter(|character| *character != '\0')
+            .map(|character| u8::try_from(character).unwrap_or(b'?')),
+    );
+    response.push(0);
+}
+
+// The challenge tokens handed out in query handshakes, which stat requests have to send back
+// This prevents the larger stat responses from being sent to spoofed addresses
+#[derive(Default)]
+struct ChallengeTokens {
+    tokens: HashMap<SocketAddr, (i32, Instant)>,
+}
+
+impl ChallengeTokens {
+    fn issue(&mut self, address: SocketAddr) -> Option<i32> {
+        let now = Instant::now();
+        self.tokens
+            .retain(|_, (_, issued_at)| now.duration_since(*issued_at) < CHALLENGE_TOKEN_LIFETIME);
+
+        if self.tokens.len() >= MAX_CHALLENGE_TOKENS
+            && !self.tokens.contains_key(&address)
+            && let Some(oldest) = self
+                .tokens
+                .iter()
+                .min_by_key(|(_, (_, issued_at))| *issued_at)
+                .map(|(address, _)| *address)
+        {
+            self.tokens.remove(&oldest);
+        }
+
+        // Clients read the token as a signed number, so only non-negative ones are used to be safe
+        let token = (getrandom::u32().ok()? >> 1) as i32;
+        self.tokens.insert(address, (token, now));
+        Some(token)
+    }
+
+    fn verify(&self, address: SocketAddr, token: i32) -> bool {
+        self.tokens
+            .get(&address)
+            .is_some_and(|(issued, issued_at)| {
+                *issued == token && issued_at.elapsed() < CHALLENGE_TOKEN_LIFETIME
+            })
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{Mutex as AsyncMutex, MutexGuard as AsyncMutexGuard};
+
+use crate::packets::StatusResponse;
+
+// Clients can send any protocol number, so only this many responses are kept
+const MAX_CACHED_PROTOCOLS: usize = 64;
+
+// Remembers the status responses of a backend, so not every status request has to reach it
+pub struct StatusCache {
+    ttl: Duration,
+    // By the protocol number of the client, as backends may answer differently depending on it
+    responses: Mutex<HashMap<i32, CachedStatus>>,
+    // When the backend last failed to answer, so it is not asked again for every status request while it is down
+    failed_at: Mutex<Option<Instant>>,
+    // Held while the backend is asked for its status, so a burst of pings only reaches it once
+    refreshing: AsyncMutex<()>,
+}
+
+struct CachedStatus {
+    fetched_at: Instant,
+    response: StatusResponse,
+}
+
+impl StatusCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            responses: Mutex::new(HashMap::new()),
+            failed_at: Mutex::new(None),
+            refreshing: AsyncMutex::new(()),
+        }
+    }
+
+    // The response for the protocol, if it was fetched recently enough
+    pub fn get(&self, protocol: i32) -> Option<StatusResponse> {
+        let responses = self.responses.lock().unwrap();
+        responses
+            .get(&protocol)
+            .filter(|cached| cached.fetched_at.elapsed() < self.ttl)
+            .map(|cached| cached.response.clone())
+    }
+
+    // The response for the protocol even if it is outdated, unless every ping should reach the backend
+    pub fn outdated(&self, protocol: i32) -> Option<StatusResponse> {
+        if self.ttl.is_zero() {
+            return None;
+        }
+        let responses = self.responses.lock().unwrap();
+        responses
+            .get(&protocol)
+            .map(|cached| cached.response.clone())
+    }
+
+    // Allows asking the backend for its status, if no one else is doing so already
+    pub fn try_refresh(&self) -> Option<AsyncMutexGuard<'_, ()>> {
+        self.refreshing.try_lock().ok()
+    }
+
+    // Waits until the backend may be asked for its status
+    pub async fn refresh(&self) -> AsyncMutexGuard<'_, ()> {
+        self.refreshing.lock().await
+    }
+
+    // The most recent response, even if it is outdated, preferring the one for the protocol
+    pub fn last_known(&self, protocol: i32) -> Option<StatusResponse> {
+        let responses = self.responses.lock().unwrap();
+        responses
+            .get(&protocol)
+            .or_else(|| responses.values().max_by_key(|cached| cached.fetched_at))
+            .map(|cached| cached.response.clone())
+    }
+
+    // Whether the backend failed to answer recently enough that it should not be asked again yet
+    pub fn recently_failed(&self) -> bool {
+        self.failed_at
+            .lock()
+            .unwrap()
+            .is_some_and(|failed_at| failed_at.elapsed() < self.ttl)
+    }
+
+    pub fn mark_failed(&self) {
+        *self.failed_at.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn insert(&self, protocol: i32, response: StatusResponse) {
+        *self.failed_at.lock().unwrap() = None;
+
+        let mut responses = self.responses.lock().unwrap();
+
+        if responses.len() >= MAX_CACHED_PROTOCOLS
+            && !responses.contains_key(&protocol)
+            && let Some(oldest) = responses
+                .iter()
+                .min_by_key(|(_, cached)| cached.fetched_at)
+                .map(|(protocol, _)| *protocol)
+        {
+            responses.remove(&oldest);
+        }
+
+        responses.insert(
+            protocol,
+            CachedStatus {
+                fetched_at: Instant::now(),
+                response,
+            },
+        );
+    }
+}
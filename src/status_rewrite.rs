@@ -0,0 +1,161 @@
+use serde_json::{Map, Value, json};
+
+use crate::{
+    config::{BackendConfig, StatusPlayerSample},
+    packets::StatusResponse,
+    types::MCString,
+};
+
+// How vanilla servers list players that don't want to be shown in the server list
+const ANONYMOUS_PLAYER_NAME: &str = "Anonymous Player";
+const ANONYMOUS_PLAYER_ID: &str = "00000000-0000-0000-0000-000000000000";
+
+// Clients refuse status responses longer than this many characters
+const MAX_STATUS_LENGTH: usize = 32767;
+
+// Applies the status rewriting rules of the backend to a status response it sent
+pub fn rewrite_status(
+    status: &StatusResponse,
+    backend: &BackendConfig,
+) -> Result<StatusResponse, &'static str> {
+    let mut json = serde_json::from_str::<Value>(status.json.as_str())
+        .map_err(|_| "The status response is not valid JSON")?;
+    let Some(json_object) = json.as_object_mut() else {
+        return Err("The status response is not a JSON object");
+    };
+
+    // The placeholders are filled with what the backend sent, before anything is replaced
+    let motd = json_object
+        .get("description")
+        .map(plain_text)
+        .unwrap_or_default();
+    let online = json_object
+        .get("players")
+        .and_then(|players| players.get("online"))
+        .and_then(Value::as_i64)
+        .unwrap_or_default();
+    let max = json_object
+        .get("players")
+        .and_then(|players| players.get("max"))
+        .and_then(Value::as_i64)
+        .unwrap_or_default();
+    let version = json_object
+        .get("version")
+        .and_then(|version| version.get("name"))
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_owned();
+
+    let fill = |template: &str| {
+        template
+            .replace("{motd}", &motd)
+            .replace("{online}", &online.to_string())
+            .replace("{max}", &max.to_string())
+            .replace("{version}", &version)
+    };
+
+    if let Some(template) = &backend.status_motd {
+        let description = if template.contains("{motd}") {
+            // The original description is kept as a component, so its formatting is not lost
+            let original = json_object
+                .get("description")
+                .cloned()
+                .unwrap_or_else(|| json!(""));
+            let mut extra = Vec::new();
+            for (index, part) in template.split("{motd}").enumerate() {
+                if index > 0 {
+                    extra.push(original.clone());
+                }
+                if !part.is_empty() {
+                    extra.push(json!({ "text": fill(part) }));
+                }
+            }
+            json!({ "text": "", "extra": extra })
+        } else {
+            json!({ "text": fill(template) })
+        };
+        json_object.insert("description".to_owned(), description);
+    }
+
+    if let Some(template) = &backend.status_version_name {
+        object_entry(json_object, "version").insert("name".to_owned(), json!(fill(template)));
+    }
+
+    if let Some(favicon) = &backend.status_favicon_data {
+        json_object.insert("favicon".to_owned(), json!(favicon));
+    }
+
+    let max_players = backend.status_max_players.map(i64::from).unwrap_or(max);
+    let max_players = match backend.status_max_players_limit {
+        Some(limit) => max_players.min(limit.into()),
+        None => max_players,
+    };
+    if max_players != max {
+        object_entry(json_object, "players").insert("max".to_owned(), json!(max_players));
+    }
+
+    if let Some(players) = json_object
+        .get_mut("players")
+        .and_then(Value::as_object_mut)
+    {
+        match backend.status_player_sample {
+            StatusPlayerSample::Keep => {}
+            StatusPlayerSample::Hide => {
+                players.remove("sample");
+            }
+            StatusPlayerSample::Anonymous => {
+                if let Some(Value::Array(sample)) = players.get_mut("sample") {
+                    sample.fill(json!({
+                        "name": ANONYMOUS_PLAYER_NAME,
+                        "id": ANONYMOUS_PLAYER_ID,
+                    }));
+                }
+            }
+        }
+    }
+
+    let mut rewritten = json.to_string();
+    if rewritten.encode_utf16().count() > MAX_STATUS_LENGTH {
+        // The player sample matters least, so it is dropped to make the rest fit
+        if let Some(players) = json.get_mut("players").and_then(Value::as_object_mut) {
+            players.remove("sample");
+        }
+        rewritten = json.to_string();
+        if rewritten.encode_utf16().count() > MAX_STATUS_LENGTH {
+            return Err("The rewritten status response is longer than clients accept");
+        }
+    }
+
+    Ok(StatusResponse {
+        json: MCString::new(rewritten)?,
+    })
+}
+
+// The object under the key, replacing whatever else is there
+fn object_entry<'a>(object: &'a mut Map<String, Value>, key: &str) -> &'a mut Map<String, Value> {
+    let entry = object.entry(key).or_insert_with(|| json!({}));
+    if !entry.is_object() {
+        *entry = json!({});
+    }
+    entry.as_object_mut().unwrap()
+}
+
+// The text of a chat component and its children, without their formatting
+pub fn plain_text(component: &Value) -> String {
+    match component {
+        Value::String(text) => text.clone(),
+        Value::Array(components) => components.iter().map(plain_text).collect(),
+        Value::Object(component) => {
+            let mut text = component
+                .get("text")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_owned();
+            if let Some(extra) = component.get("extra") {
+                text.push_str(&plain_text(extra));
+            }
+            text
+        }
+        _ => String::new(),
+    }
+}
@@ -42,7 +42,8 @@ impl TryFrom<i32> for VarInt {
         let bytes_needed = if value == 0 {
             1
         } else {
-            let mut temp = value;
+            // Negative values take up all 5 bytes, shifting them as signed numbers would never reach 0
+            let mut temp = value as u32;
             let mut count = 0;
             while temp != 0 {
                 temp >>= 7;
@@ -228,6 +229,22 @@ impl Deref for Uuid {
     }
 }
 
+// Formats the uuid with hyphens, like 069a79f4-44e9-4726-a5be-fca90e38aaf5
+impl std::fmt::Display for Uuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hex = format!("{:032x}", self.0);
+        write!(
+            f,
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+}
+
 impl MCData for Uuid {
     async fn read<R: AsyncReadExt + Unpin>(reader: &mut R) -> tokio::io::Result<Self> {
         Ok(Uuid(reader.read_u128().await?))
@@ -244,3 +261,85 @@ impl MCData for Uuid {
         std::mem::size_of::<Self>()
     }
 }
+
+// A VarInt length prefixed byte array
+#[derive(Clone)]
+pub struct ByteArray(pub Vec<u8>);
+
+impl Deref for ByteArray {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl MCData for ByteArray {
+    async fn read<R: AsyncReadExt + Unpin>(reader: &mut R) -> tokio::io::Result<Self> {
+        let length = VarInt::read(reader).await?;
+
+        if *length < 0 {
+            return Err(tokio::io::Error::new(
+                tokio::io::ErrorKind::InvalidData,
+                format!("Byte array length {} is invalid", *length),
+            ));
+        }
+
+        // The buffer only grows with the bytes that are actually there, not the length the peer claims
+        let mut buffer = Vec::new();
+        let read = reader.take(*length as u64).read_to_end(&mut buffer).await?;
+        if read != *length as usize {
+            return Err(tokio::io::Error::new(
+                tokio::io::ErrorKind::UnexpectedEof,
+                format!("Byte array is {read} bytes long instead of {}", *length),
+            ));
+        }
+        Ok(ByteArray(buffer))
+    }
+
+    async fn write<W>(&self, writer: &mut W) -> tokio::io::Result<()>
+    where
+        W: AsyncWriteExt + Unpin,
+    {
+        VarInt::try_from(self.0.len() as i32)
+            .map_err(|e| tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, e))?
+            .write(writer)
+            .await?;
+        writer.write_all(&self.0).await
+    }
+
+    fn byte_size(&self) -> usize {
+        VarInt::try_from(self.0.len() as i32).unwrap().byte_size() + self.0.len()
+    }
+}
+
+// The chat signing key of a player, as introduced in 1.19
+#[derive(Clone)]
+pub struct PlayerKey {
+    pub expires_at: i64, // Unix timestamp in milliseconds
+    pub public_key: ByteArray,
+    pub signature: ByteArray,
+}
+
+impl MCData for PlayerKey {
+    async fn read<R: AsyncReadExt + Unpin>(reader: &mut R) -> tokio::io::Result<Self> {
+        Ok(PlayerKey {
+            expires_at: reader.read_i64().await?,
+            public_key: ByteArray::read(reader).await?,
+            signature: ByteArray::read(reader).await?,
+        })
+    }
+
+    async fn write<W>(&self, writer: &mut W) -> tokio::io::Result<()>
+    where
+        W: AsyncWriteExt + Unpin,
+    {
+        writer.write_i64(self.expires_at).await?;
+        self.public_key.write(writer).await?;
+        self.signature.write(writer).await
+    }
+
+    fn byte_size(&self) -> usize {
+        8 + self.public_key.byte_size() + self.signature.byte_size()
+    }
+}